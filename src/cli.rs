@@ -0,0 +1,97 @@
+use clap::builder::BoolishValueParser;
+use clap::Parser;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tracing_subscriber::filter::LevelFilter;
+
+/// Drink machine server. Anything given on the command line wins over the
+/// matching environment variable.
+#[derive(Parser)]
+#[command(
+    version,
+    after_help = "Machine configuration is read from the environment, or from --config-file:
+  BUB_SLOT_ADDRESSES           OWFS slot addresses, comma or newline separated. primary:id|backup:id
+                               drives two redundant devices as one slot
+  BUB_SLOT_ADDRESSES_FILE      File with one OWFS slot address per line
+  BUB_OWFS_GPIO_DEVICES        DS2408 channels with a slot each (deviceid:channel), comma separated
+  BUB_VEND_PINS                GPIO vend pins (pin[:chip]), comma separated
+  BUB_STOCKED_PINS             GPIO stocked pins, one per vend pin
+  BUB_CAM_PINS                 GPIO cam pins, optional, with an empty entry for slots without one
+  BUB_MOTOR_FAULT_PINS         GPIO motor driver fault pins, optional
+  BUB_ACTIVE_LOW               1 if the stocked switches are active-low
+  BUB_SLOT_STOCKED_INVERT      Slots whose stocked reading is flipped in software, comma separated
+  BUB_GPIO_CHIP_PATH_TEMPLATE  gpiochip path, {} is the chip number
+  BUB_LATCH_PIN                GPIO pin for the motor power latch, optional
+  BUB_LATCH_WATCHDOG_MS        How long the latch stays open without a heartbeat (default 5000)
+  BUB_TEMP_ADDRESS             OWFS temperature sensor address, optional
+  BUB_TEMP_UNIT_MILLIDEGREES   1 if the temperature sensor reports thousandths of a degree
+  BUB_DROP_DELAY               Motor run time in ms for slots without a cam (default 500, max 60000)
+  BUB_RT_POLICY                Real-time scheduling for motor writes, fifo or rr (default fifo)
+  BUB_STOCK_POLL_MS            How often to check slots for stock changes (default 1000, 0 = never)
+  BUB_OWFS_VERIFY_RETRY        Times to read back an OWFS motor write before giving up (default 3, 0 = don't)
+  BUB_STOCKED_DEBOUNCE_MS      How long a stock change has to last to count (default 50)
+  BUB_TEMP_ERROR_THRESHOLD     Failed temperature reads in a row before reporting null (default 3)
+  BUB_MAX_COUNT_PER_DROP       Most drinks one /drop can ask for with count (default 5)
+  BUB_MULTI_DROP_INTERVAL_MS   Pause between drops asked for with count (default 1000)
+  BUB_METADATA_MAX_BYTES       Largest metadata a /drop can carry, as JSON (default 1024)
+  BUB_CAM_HISTORY_SIZE         Cam pulses to remember per slot (default 20)
+  BUB_MACHINE_ID               Reported by /slots and to Sentry (default /etc/machine-id)
+  BUB_ALLOW_REMOTE_REBOOT      1 to allow POST /machine/reboot
+  BUB_API_KEY                  Bearer token required to open /ws and /debug/*
+  BUB_ENABLE_DEBUG             1 to enable /debug/memory and /debug/threads
+  BUB_ENABLE_PPROF             1 to enable /debug/pprof (needs the pprof feature)
+  BUB_SENTRY_DSN               Where to report crashes (needs the sentry feature)
+  BUB_ACCESS_LOG_FORMAT        actix-web Logger format for the access log, %{client_ip}xi
+                               is the client address seen through trusted proxies
+  BUB_TRUSTED_PROXIES          Proxy IPs/CIDRs whose X-Forwarded-For is believed
+  BUB_SIMULATE                 1 to enable the testing knobs below
+  BUB_FAULT_SLOTS              Slots whose motor always fails (slot[:motor_on|:motor_off])
+  BUB_MOCK                     1 to use pretend slots and temperature instead of the hardware
+                               (needs the mock feature, always on without owfs and gpio)
+  BUB_MOCK_SLOTS               How many pretend slots (default 4)
+  BUB_MOCK_STOCK               Drops before each pretend slot runs out, until a reload (default 5)
+  BUB_MOCK_CAM_MS              How long a pretend cam takes to turn (default 1500)"
+)]
+pub struct Cli {
+    /// Address to listen on
+    #[arg(long, env = "BUB_BIND_ADDRESS", default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+    /// Port to listen on. Given several (comma separated), the first only
+    /// serves /drop, /health and /slots, and the rest serve everything.
+    #[arg(
+        long,
+        env = "BUB_BIND_PORT",
+        default_value = "8080",
+        value_delimiter = ','
+    )]
+    pub port: Vec<u16>,
+    /// Pending connection queue length, defaults to actix's 1024. Going
+    /// past net.core.somaxconn (4096 on recent kernels) needs that raised
+    /// too, or the kernel quietly caps it.
+    #[arg(long, env = "BUB_LISTEN_BACKLOG")]
+    pub listen_backlog: Option<u32>,
+    /// How long idle connections stay open, 0 to close them after every
+    /// request. Longer saves dashboards reconnecting, but every idle
+    /// connection holds a file descriptor, which a Pi doesn't have many of.
+    #[arg(long, env = "BUB_KEEPALIVE_SECONDS", default_value_t = 5)]
+    pub keepalive_seconds: u64,
+    /// Largest request body accepted, JSON, form or otherwise. Nothing we
+    /// take comes close, and the default keeps a bad client from eating
+    /// the RAM.
+    #[arg(long, env = "BUB_MAX_REQUEST_BODY_BYTES", default_value_t = 65536)]
+    pub max_request_body_bytes: usize,
+    /// Most verbose log level to print (off, error, warn, info, debug, trace)
+    #[arg(long, env = "BUB_LOG_LEVEL", default_value = "info")]
+    pub log_level: LevelFilter,
+    /// Go through the motions of every drop without switching any motors
+    #[arg(long, env = "BUB_DRY_RUN", value_parser = BoolishValueParser::new())]
+    pub dry_run: bool,
+    /// TOML file with the slots, latch, drop delay and temperature sensor.
+    /// Whatever it sets wins over the matching variable below, and slots
+    /// listed in it replace any from the environment.
+    #[arg(long = "config-file", visible_alias = "config", env = "BUB_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Check the machine configuration, print a summary and exit
+    #[arg(long)]
+    pub validate_config: bool,
+}
@@ -0,0 +1,1380 @@
+#[cfg(any(test, feature = "mock"))]
+use crate::mock::MockSlot;
+use crate::routes::client_ip::TrustedProxies;
+use crate::routes::events::{MachineEvent, EVENT_BUFFER};
+use crate::routes::history::{History, StockEventLog};
+use crate::routes::poller::Monitors;
+use crate::scheduler::{probe_realtime, RtPolicy};
+use arc_swap::ArcSwap;
+#[cfg(feature = "gpio")]
+use gpio_cdev::{Chip, EventRequestFlags, Line, LineEventHandle, LineHandle, LineRequestFlags};
+use ipnet::IpNet;
+use std::fmt::Display;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+#[cfg(feature = "gpio")]
+use std::sync::mpsc::{channel, Sender};
+#[cfg(feature = "gpio")]
+use std::sync::PoisonError;
+use std::sync::{Arc, Mutex as StdMutex};
+#[cfg(feature = "gpio")]
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(feature = "gpio")]
+use std::time::Instant;
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "gpio")]
+use tracing::error;
+use tracing::{info, warn};
+
+mod file;
+pub use file::Settings;
+
+pub enum SlotConfig {
+    #[cfg(feature = "owfs")]
+    OWFS(String),
+    /// One channel of a DS2408 8-channel expander, driving the motor through
+    /// `PIO.BYTE` and reading the switch from `sensed.BYTE`
+    #[cfg(feature = "owfs")]
+    OwfsGpio { device_id: String, channel: u8 },
+    /// Two OWFS devices wired to the same slot. Both get driven, and the
+    /// slot is stocked if either says so.
+    #[cfg(feature = "owfs")]
+    OwfsRedundant { primary: String, backup: String },
+    #[cfg(feature = "gpio")]
+    GPIO {
+        vend: LineHandle,
+        /// Requested for edge events so the stock monitor can wait on it
+        stocked: LineEventHandle,
+        cam: Option<Line>,
+        /// The motor driver's overcurrent output
+        fault: Option<Line>,
+    },
+    /// No hardware at all, see `BUB_MOCK`
+    #[cfg(any(test, feature = "mock"))]
+    Mock(MockSlot),
+}
+
+impl Display for SlotConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "owfs")]
+            Self::OWFS(id) => write!(f, "{}", id),
+            #[cfg(feature = "owfs")]
+            Self::OwfsGpio { device_id, channel } => write!(f, "{}:{}", device_id, channel),
+            #[cfg(feature = "owfs")]
+            Self::OwfsRedundant { primary, backup } => write!(f, "{}|{}", primary, backup),
+            #[cfg(feature = "gpio")]
+            Self::GPIO {
+                vend, stocked, cam, ..
+            } => {
+                write!(
+                    f,
+                    "{}.{}{}",
+                    vend.line().offset(),
+                    stocked.line().offset(),
+                    cam.as_ref()
+                        .map(|cam| format!(".{}", cam.offset()))
+                        .unwrap_or_default()
+                )
+            }
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock(mock) => write!(f, "mock{}", mock.number),
+        }
+    }
+}
+
+impl SlotConfig {
+    /// Whether `other` is wired to the same hardware the same way, so a
+    /// reload can keep this slot's open lines rather than request them
+    /// again. Mock slots never are, so that a reload restocks them.
+    pub fn is_hardware_equivalent(&self, other: &SlotConfig) -> bool {
+        match (self, other) {
+            #[cfg(feature = "owfs")]
+            (Self::OWFS(id), Self::OWFS(other_id)) => id == other_id,
+            #[cfg(feature = "owfs")]
+            (
+                Self::OwfsGpio { device_id, channel },
+                Self::OwfsGpio {
+                    device_id: other_device_id,
+                    channel: other_channel,
+                },
+            ) => device_id == other_device_id && channel == other_channel,
+            #[cfg(feature = "owfs")]
+            (
+                Self::OwfsRedundant { primary, backup },
+                Self::OwfsRedundant {
+                    primary: other_primary,
+                    backup: other_backup,
+                },
+            ) => primary == other_primary && backup == other_backup,
+            #[cfg(feature = "gpio")]
+            (Self::GPIO { .. }, Self::GPIO { .. }) => self
+                .gpio_pins()
+                .is_some_and(|pins| other.gpio_pins() == Some(pins)),
+            #[cfg(any(feature = "owfs", not(feature = "gpio"), test, feature = "mock"))]
+            _ => false,
+        }
+    }
+
+    /// What the slot is wired through, for error messages
+    pub fn backend(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "owfs")]
+            Self::OWFS(_) | Self::OwfsRedundant { .. } => "OWFS",
+            #[cfg(feature = "owfs")]
+            Self::OwfsGpio { .. } => "DS2408",
+            #[cfg(feature = "gpio")]
+            Self::GPIO { .. } => "GPIO",
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock(_) => "Mock",
+        }
+    }
+
+    /// Where a GPIO slot's lines are, `None` for other slots or when the
+    /// kernel won't say whether the switch is active low
+    #[cfg(feature = "gpio")]
+    fn gpio_pins(&self) -> Option<GpioPins> {
+        match self {
+            Self::GPIO {
+                vend,
+                stocked,
+                cam,
+                fault,
+            } => Some(GpioPins {
+                vend: vend.line().clone(),
+                stocked: stocked.line().clone(),
+                active_low: stocked.line().info().ok()?.is_active_low(),
+                cam: cam.clone(),
+                fault: fault.clone(),
+            }),
+            #[cfg(any(feature = "owfs", test, feature = "mock"))]
+            _ => None,
+        }
+    }
+}
+
+/// A GPIO slot's lines before any of them are requested, so a reload can
+/// tell whether an open slot already has them
+#[cfg(feature = "gpio")]
+struct GpioPins {
+    vend: Line,
+    stocked: Line,
+    active_low: bool,
+    cam: Option<Line>,
+    fault: Option<Line>,
+}
+
+#[cfg(feature = "gpio")]
+impl PartialEq for GpioPins {
+    fn eq(&self, other: &Self) -> bool {
+        let same_optional = |line: &Option<Line>, other: &Option<Line>| match (line, other) {
+            (Some(line), Some(other)) => same_line(line, other),
+            (None, None) => true,
+            _ => false,
+        };
+        same_line(&self.vend, &other.vend)
+            && same_line(&self.stocked, &other.stocked)
+            && self.active_low == other.active_low
+            && same_optional(&self.cam, &other.cam)
+            && same_optional(&self.fault, &other.fault)
+    }
+}
+
+/// Whether `line` and `other` are the same pin on the same chip
+#[cfg(feature = "gpio")]
+fn same_line(line: &Line, other: &Line) -> bool {
+    line.offset() == other.offset() && line.chip().path() == other.chip().path()
+}
+
+#[cfg(feature = "gpio")]
+pub struct Latch {
+    pin: Arc<LineHandle>,
+    worker: StdMutex<LatchWorker>,
+    /// How long an `open()` keeps the latch open for
+    watchdog: Duration,
+}
+
+#[cfg(feature = "gpio")]
+struct LatchWorker {
+    thread: JoinHandle<()>,
+    sender: Sender<Instant>,
+}
+
+#[cfg(feature = "gpio")]
+#[derive(Debug)]
+pub enum LatchError {
+    ThreadDied,
+}
+
+#[cfg(feature = "gpio")]
+impl Display for LatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ThreadDied => write!(f, "Latch thread died and had to be restarted"),
+        }
+    }
+}
+
+#[cfg(feature = "gpio")]
+impl std::error::Error for LatchError {}
+
+#[cfg(feature = "gpio")]
+impl LatchWorker {
+    fn spawn(pin: Arc<LineHandle>) -> Self {
+        let (sender, receiver) = channel::<Instant>();
+        let thread = thread::spawn(move || {
+            loop {
+                // Hung up on by `Latch::drop`
+                let instant = match receiver.recv() {
+                    Ok(instant) => instant,
+                    Err(_) => return,
+                };
+                let now = Instant::now();
+                if now > instant {
+                    continue;
+                }
+                pin.set_value(1).unwrap();
+                thread::sleep(instant.duration_since(now));
+                while let Ok(instant) = receiver.try_recv() {
+                    let now = Instant::now();
+                    if now > instant {
+                        continue;
+                    }
+                    // Let this run finish first
+                    thread::sleep(instant.duration_since(now));
+                }
+                pin.set_value(0).unwrap();
+            }
+        });
+        LatchWorker { thread, sender }
+    }
+}
+
+#[cfg(feature = "gpio")]
+impl Latch {
+    fn new(pin: LineHandle, watchdog: Duration) -> Self {
+        let pin = Arc::new(pin);
+        Latch {
+            worker: StdMutex::new(LatchWorker::spawn(pin.clone())),
+            pin,
+            watchdog,
+        }
+    }
+    /// Opens the latch, or keeps it open, for another `watchdog()`. Long
+    /// drops have to keep calling this or it shuts on them.
+    pub fn open(&self) -> Result<(), LatchError> {
+        let deadline = Instant::now() + self.watchdog;
+        // A panic while holding this can't leave the worker half-swapped,
+        // and a dead thread gets replaced below anyway
+        let mut worker = self.worker.lock().unwrap_or_else(PoisonError::into_inner);
+        if worker.sender.send(deadline).is_ok() {
+            return Ok(());
+        }
+
+        // The receiver only goes away if the thread died, so find out why
+        // and bring up a fresh one before reporting it
+        let dead = std::mem::replace(&mut *worker, LatchWorker::spawn(self.pin.clone()));
+        match dead.thread.join() {
+            Err(panic) => {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                error!("Latch thread panicked: {}", reason);
+            }
+            Ok(()) => error!("Latch thread exited unexpectedly"),
+        }
+        if worker.sender.send(deadline).is_err() {
+            error!("Restarted latch thread isn't listening either!");
+        }
+        Err(LatchError::ThreadDied)
+    }
+    /// The latch on `BUB_LATCH_PIN`, or `None` when it isn't set. Doesn't
+    /// need the rest of the config.
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, ConfigError> {
+        let watchdog =
+            Duration::from_millis(setting_number(settings, "BUB_LATCH_WATCHDOG_MS", 5000)?);
+        let pin = match settings.var("BUB_LATCH_PIN") {
+            Ok(pin) => pin,
+            Err(_) => return Ok(None),
+        };
+        let handle = lookup_pin(settings, "BUB_LATCH_PIN", &pin)?
+            .request(LineRequestFlags::OUTPUT, 0, "bubbler-latch")
+            .map_err(gpio_error("BUB_LATCH_PIN", &pin))?;
+        Ok(Some(Latch::new(handle, watchdog)))
+    }
+    /// Like `from_settings`, but hands back `previous` instead when it's
+    /// already on the same pin with the same watchdog
+    fn from_settings_keeping(
+        settings: &Settings,
+        previous: Option<&Arc<Latch>>,
+    ) -> Result<Option<Arc<Self>>, ConfigError> {
+        if let (Some(previous), Ok(pin)) = (previous, settings.var("BUB_LATCH_PIN")) {
+            let watchdog =
+                Duration::from_millis(setting_number(settings, "BUB_LATCH_WATCHDOG_MS", 5000)?);
+            let line = lookup_pin(settings, "BUB_LATCH_PIN", &pin)?;
+            if watchdog == previous.watchdog && same_line(&line, previous.pin.line()) {
+                info!("Kept the latch on pin {}", previous.pin());
+                return Ok(Some(previous.clone()));
+            }
+        }
+        Ok(Self::from_settings(settings)?.map(Arc::new))
+    }
+    pub fn is_healthy(&self) -> bool {
+        // Same as open(), a panic elsewhere shouldn't take the health check
+        // down with it
+        !self
+            .worker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .thread
+            .is_finished()
+    }
+    pub fn pin(&self) -> u32 {
+        self.pin.line().offset()
+    }
+    pub fn watchdog(&self) -> Duration {
+        self.watchdog
+    }
+}
+
+/// Waits for the worker to shut the latch and let go of the pin, so a config
+/// reload can request it again. Blocks for up to `watchdog` if it's open.
+#[cfg(feature = "gpio")]
+impl Drop for Latch {
+    fn drop(&mut self) {
+        let worker = self
+            .worker
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        worker.sender = channel().0;
+        while !worker.thread.is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConfigData {
+    /// `BUB_MACHINE_ID`, or `/etc/machine-id` when that isn't set
+    pub machine_id: String,
+    /// `None` for machines without a sensor
+    pub temperature_id: Option<String>,
+    pub slots: Vec<Arc<SlotConfig>>,
+    #[cfg(feature = "gpio")]
+    pub latch: Option<Arc<Latch>>,
+    pub drop_delay: Duration,
+    /// Real-time policy the motor writes run under
+    pub rt_policy: RtPolicy,
+    pub allow_remote_reboot: bool,
+    /// How many cam pulses to remember per slot
+    pub cam_history_size: usize,
+    /// How often to check for slots being emptied or restocked, 0 to not
+    pub stock_poll_ms: u64,
+    /// How long a stocked switch has to hold a new reading before we believe it
+    pub stocked_debounce_ms: u64,
+    /// Most a single `/drop` can ask for with `count`
+    pub max_count_per_drop: u32,
+    /// Pause between the drops of a multi-drop
+    pub multi_drop_interval_ms: u64,
+    /// Largest `metadata` a drop can carry, as JSON
+    pub metadata_max_bytes: usize,
+    /// How many times to read back and rewrite an OWFS motor's PIO
+    #[cfg(feature = "owfs")]
+    pub owfs_verify_retry: u32,
+    /// The sensor reports thousandths of a degree, like `4250` for 4.25°C
+    #[cfg(feature = "owfs")]
+    pub temp_unit_millidegrees: bool,
+    /// Failed temperature reads in a row before we report no temperature
+    /// instead of the last good one
+    pub temp_error_threshold: u32,
+    /// Required as a bearer token on `/ws` when set, and always required for
+    /// the debug endpoints
+    pub api_key: Option<String>,
+    /// Turns on `/debug/memory` and `/debug/threads`
+    pub enable_debug: bool,
+    #[cfg(feature = "pprof")]
+    pub enable_pprof: bool,
+    pub trusted_proxies: TrustedProxies,
+    /// Simulated motor failures from `BUB_FAULT_SLOTS`, empty unless
+    /// `BUB_SIMULATE=1`
+    pub faults: Vec<Fault>,
+    /// Slots whose stocked switch reads backwards, from `BUB_SLOT_STOCKED_INVERT`
+    pub stocked_invert: Vec<usize>,
+    /// The slots and temperature sensor are pretend, from `BUB_MOCK`
+    #[cfg(any(test, feature = "mock"))]
+    pub mock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultOperation {
+    Any,
+    MotorOn,
+    MotorOff,
+}
+
+impl Display for FaultOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::MotorOn => write!(f, "motor_on"),
+            Self::MotorOff => write!(f, "motor_off"),
+        }
+    }
+}
+
+/// Makes `run_motor` fail for a slot, for exercising error handling without
+/// having to break the hardware
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub slot: usize,
+    pub operation: FaultOperation,
+}
+
+impl Fault {
+    pub fn applies_to(&self, slot: usize, state: bool) -> bool {
+        self.slot == slot
+            && match self.operation {
+                FaultOperation::Any => true,
+                FaultOperation::MotorOn => state,
+                FaultOperation::MotorOff => !state,
+            }
+    }
+}
+
+/// `BUB_GPIO_CHIP_PATH_TEMPLATE` with the chip ID substituted for `{}`, for
+/// boards whose gpiochips don't live at `/dev/gpiochipN`
+#[cfg(feature = "gpio")]
+fn chip_path(settings: &Settings, chip_id: u32) -> String {
+    settings
+        .var("BUB_GPIO_CHIP_PATH_TEMPLATE")
+        .unwrap_or_else(|_| "/dev/gpiochip{}".to_string())
+        .replace("{}", &chip_id.to_string())
+}
+
+/// Finds the line for a `pin[:chip]` entry from `variable`
+#[cfg(feature = "gpio")]
+fn lookup_pin(
+    settings: &Settings,
+    variable: &'static str,
+    spec: &str,
+) -> Result<Line, ConfigError> {
+    let invalid = || ConfigError::InvalidPin {
+        variable,
+        spec: spec.to_string(),
+    };
+    let mut parts = spec.trim().split(':');
+    let pin = parts
+        .next()
+        .and_then(|pin| pin.parse::<u32>().ok())
+        .ok_or_else(invalid)?;
+    let chip_id = match parts.next() {
+        Some(chip_id) => chip_id.parse::<u32>().map_err(|_| invalid())?,
+        None => 0,
+    };
+    Chip::new(chip_path(settings, chip_id))
+        .and_then(|mut chip| chip.get_line(pin))
+        .map_err(gpio_error(variable, spec))
+}
+
+#[cfg(feature = "gpio")]
+fn gpio_error(variable: &'static str, spec: &str) -> impl FnOnce(gpio_cdev::Error) -> ConfigError {
+    let spec = spec.to_string();
+    move |error| ConfigError::Gpio {
+        variable,
+        spec,
+        error,
+    }
+}
+
+/// `variable` parsed as a number, or `default` when it isn't set
+fn setting_number<T: FromStr>(
+    settings: &Settings,
+    variable: &'static str,
+    default: T,
+) -> Result<T, ConfigError> {
+    match settings.var(variable) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| ConfigError::InvalidNumber { variable, value }),
+        Err(_) => Ok(default),
+    }
+}
+
+fn slot_addresses(settings: &Settings) -> Result<Option<Vec<String>>, ConfigError> {
+    if let Ok(addresses) = settings.var("BUB_SLOT_ADDRESSES") {
+        return Ok(Some(
+            addresses
+                .split([',', '\n'])
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ));
+    }
+    let path = match settings.var("BUB_SLOT_ADDRESSES_FILE") {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    let addresses = fs::read_to_string(&path)
+        .map_err(|error| ConfigError::SlotAddressesFile { path, error })?;
+    Ok(Some(
+        addresses
+            .lines()
+            .map(str::trim)
+            .filter(|address| !address.is_empty() && !address.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+fn machine_id(settings: &Settings) -> String {
+    settings
+        .var("BUB_MACHINE_ID")
+        .ok()
+        .filter(|id| !id.is_empty())
+        .or_else(|| {
+            fs::read_to_string("/etc/machine-id")
+                .ok()
+                .map(|id| id.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+const DEFAULT_DROP_DELAY_MS: u64 = 500;
+/// Anything longer is a typo, nothing takes a minute to fall out
+pub const MAX_DROP_DELAY_MS: u64 = 60_000;
+
+fn drop_delay(settings: &Settings) -> Result<Duration, ConfigError> {
+    let drop_delay = match settings.var("BUB_DROP_DELAY") {
+        Ok(delay) => delay.parse::<u64>().unwrap_or_else(|_| {
+            warn!(
+                "BUB_DROP_DELAY {:?} isn't a number, using the default of {}ms",
+                delay, DEFAULT_DROP_DELAY_MS
+            );
+            DEFAULT_DROP_DELAY_MS
+        }),
+        Err(_) => {
+            info!(
+                "BUB_DROP_DELAY isn't set, using the default of {}ms",
+                DEFAULT_DROP_DELAY_MS
+            );
+            DEFAULT_DROP_DELAY_MS
+        }
+    };
+    if drop_delay > MAX_DROP_DELAY_MS {
+        return Err(ConfigError::DropDelayTooLong(drop_delay));
+    }
+    Ok(Duration::from_millis(drop_delay))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    PinCountMismatch {
+        vend: usize,
+        stocked: usize,
+        cam: usize,
+        fault: usize,
+    },
+    FeatureNotEnabled {
+        feature: &'static str,
+        variable: &'static str,
+    },
+    InvalidFault(String),
+    InvalidTrustedProxy(String),
+    InvalidOwfsGpio(String),
+    InvalidRedundantSlot(String),
+    InvalidStockedInvert(String),
+    ConfigFile {
+        path: String,
+        reason: String,
+    },
+    InvalidRtPolicy(String),
+    SlotAddressesFile {
+        path: String,
+        error: std::io::Error,
+    },
+    DropDelayTooLong(u64),
+    MissingVariable(&'static str),
+    InvalidNumber {
+        variable: &'static str,
+        value: String,
+    },
+    #[cfg(feature = "gpio")]
+    InvalidPin {
+        variable: &'static str,
+        spec: String,
+    },
+    /// The pin parsed fine, but the kernel wouldn't give it to us
+    #[cfg(feature = "gpio")]
+    Gpio {
+        variable: &'static str,
+        spec: String,
+        error: gpio_cdev::Error,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PinCountMismatch {
+                vend,
+                stocked,
+                cam,
+                fault,
+            } => {
+                write!(
+                    f,
+                    "BUB_VEND_PINS has {} pins and BUB_STOCKED_PINS has {} pins",
+                    vend, stocked
+                )?;
+                if cam > vend.min(stocked) {
+                    write!(f, ", but BUB_CAM_PINS has {} pins", cam)?;
+                }
+                if fault > vend.min(stocked) {
+                    write!(f, ", but BUB_MOTOR_FAULT_PINS has {} pins", fault)?;
+                }
+                if vend < stocked {
+                    write!(f, " (BUB_VEND_PINS is too short)")
+                } else if stocked < vend {
+                    write!(f, " (BUB_STOCKED_PINS is too short)")
+                } else if cam > vend {
+                    write!(f, " (BUB_CAM_PINS is too long)")
+                } else {
+                    write!(f, " (BUB_MOTOR_FAULT_PINS is too long)")
+                }
+            }
+            Self::FeatureNotEnabled { feature, variable } => write!(
+                f,
+                "{} is set, but bubbler was built without the {} feature",
+                variable, feature
+            ),
+            Self::InvalidFault(spec) => write!(
+                f,
+                "BUB_FAULT_SLOTS entry {:?} isn't slot[:motor_on|:motor_off]",
+                spec
+            ),
+            Self::InvalidTrustedProxy(spec) => write!(
+                f,
+                "BUB_TRUSTED_PROXIES entry {:?} isn't an IP address or CIDR",
+                spec
+            ),
+            Self::InvalidOwfsGpio(spec) => write!(
+                f,
+                "BUB_OWFS_GPIO_DEVICES entry {:?} isn't deviceid:channel with a channel from 0 to 7",
+                spec
+            ),
+            Self::InvalidRedundantSlot(spec) => write!(
+                f,
+                "BUB_SLOT_ADDRESSES entry {:?} isn't primary:id|backup:id",
+                spec
+            ),
+            Self::InvalidStockedInvert(spec) => write!(
+                f,
+                "BUB_SLOT_STOCKED_INVERT entry {:?} isn't a configured slot",
+                spec
+            ),
+            Self::InvalidRtPolicy(policy) => {
+                write!(f, "BUB_RT_POLICY is {:?}, not fifo or rr", policy)
+            }
+            Self::ConfigFile { path, reason } => {
+                write!(f, "Couldn't load config file {}: {}", path, reason)
+            }
+            Self::SlotAddressesFile { path, error } => write!(
+                f,
+                "Couldn't read BUB_SLOT_ADDRESSES_FILE {}: {}",
+                path, error
+            ),
+            Self::DropDelayTooLong(delay) => write!(
+                f,
+                "BUB_DROP_DELAY is {}ms, but it can be at most {}ms",
+                delay, MAX_DROP_DELAY_MS
+            ),
+            Self::MissingVariable(variable) => write!(f, "{} isn't set", variable),
+            Self::InvalidNumber { variable, value } => {
+                write!(f, "{} is {:?}, which isn't a number", variable, value)
+            }
+            #[cfg(feature = "gpio")]
+            Self::InvalidPin { variable, spec } => {
+                write!(f, "{} entry {:?} isn't pin[:chip]", variable, spec)
+            }
+            #[cfg(feature = "gpio")]
+            Self::Gpio {
+                variable,
+                spec,
+                error,
+            } => write!(
+                f,
+                "Couldn't set up {} entry {:?}: {}",
+                variable, spec, error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "gpio")]
+            Self::Gpio { error, .. } => Some(error),
+            Self::SlotAddressesFile { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "owfs")]
+fn owfs_slots(addresses: Vec<String>) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    addresses
+        .into_iter()
+        .map(|address| {
+            if !address.contains('|') {
+                return Ok(Arc::new(SlotConfig::OWFS(address)));
+            }
+            let invalid = || ConfigError::InvalidRedundantSlot(address.clone());
+            let (primary, backup) = address.split_once('|').ok_or_else(invalid)?;
+            let primary = primary
+                .trim()
+                .strip_prefix("primary:")
+                .ok_or_else(invalid)?;
+            let backup = backup.trim().strip_prefix("backup:").ok_or_else(invalid)?;
+            if primary.is_empty() || backup.is_empty() || backup.contains('|') {
+                return Err(invalid());
+            }
+            Ok(Arc::new(SlotConfig::OwfsRedundant {
+                primary: primary.to_string(),
+                backup: backup.to_string(),
+            }))
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "owfs"))]
+fn owfs_slots(_addresses: Vec<String>) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    Err(ConfigError::FeatureNotEnabled {
+        feature: "owfs",
+        variable: "BUB_SLOT_ADDRESSES",
+    })
+}
+
+/// `BUB_OWFS_GPIO_DEVICES`, `deviceid:channel` for each DS2408 channel with
+/// a slot on it
+#[cfg(feature = "owfs")]
+fn owfs_gpio_slots(settings: &Settings) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    let devices = match settings.var("BUB_OWFS_GPIO_DEVICES") {
+        Ok(devices) => devices,
+        Err(_) => return Ok(Vec::new()),
+    };
+    devices
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| {
+            let invalid = || ConfigError::InvalidOwfsGpio(spec.to_string());
+            let (device_id, channel) = spec.rsplit_once(':').ok_or_else(invalid)?;
+            let channel = channel
+                .parse::<u8>()
+                .ok()
+                .filter(|channel| *channel < 8)
+                .ok_or_else(invalid)?;
+            Ok(Arc::new(SlotConfig::OwfsGpio {
+                device_id: device_id.to_string(),
+                channel,
+            }))
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "owfs"))]
+fn owfs_gpio_slots(settings: &Settings) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    if settings.var("BUB_OWFS_GPIO_DEVICES").is_ok() {
+        return Err(ConfigError::FeatureNotEnabled {
+            feature: "owfs",
+            variable: "BUB_OWFS_GPIO_DEVICES",
+        });
+    }
+    Ok(Vec::new())
+}
+
+/// The slots from `BUB_VEND_PINS` and friends. Any slot in `previous` that
+/// already has the lines one needs is used as it is.
+#[cfg(feature = "gpio")]
+fn gpio_slots(
+    settings: &Settings,
+    previous: &[Arc<SlotConfig>],
+) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    let mut slots: Vec<Arc<SlotConfig>> = Vec::new();
+    let vend = settings
+        .var("BUB_VEND_PINS")
+        .map_err(|_| ConfigError::MissingVariable("BUB_SLOT_ADDRESSES or BUB_VEND_PINS"))?;
+    let vend: Vec<&str> = vend.split(',').collect();
+    let stocked = settings
+        .var("BUB_STOCKED_PINS")
+        .map_err(|_| ConfigError::MissingVariable("BUB_STOCKED_PINS"))?;
+    let stocked: Vec<&str> = stocked.split(',').collect();
+    let optional_pins = |variable: &str| -> Vec<String> {
+        settings
+            .var(variable)
+            .ok()
+            .into_iter()
+            .flat_map(|pins| pins.split(',').map(str::to_string).collect::<Vec<_>>())
+            .collect()
+    };
+    let cam = optional_pins("BUB_CAM_PINS");
+    let fault = optional_pins("BUB_MOTOR_FAULT_PINS");
+    // Slots without a cam or fault pin are fine, but every slot needs both a
+    // motor and a switch
+    if vend.len() != stocked.len() || cam.len() > vend.len() || fault.len() > vend.len() {
+        return Err(ConfigError::PinCountMismatch {
+            vend: vend.len(),
+            stocked: stocked.len(),
+            cam: cam.len(),
+            fault: fault.len(),
+        });
+    }
+    // An empty entry skips a slot, like `13,,14`
+    let cam = cam
+        .into_iter()
+        .map(|pin| Some(pin).filter(|pin| !pin.trim().is_empty()));
+    let mut fault = fault
+        .into_iter()
+        .map(|pin| Some(pin).filter(|pin| !pin.trim().is_empty()));
+    let active_low = settings.var("BUB_ACTIVE_LOW").unwrap_or("0".to_string()) == "1";
+    let mut input_flags = LineRequestFlags::INPUT;
+    if active_low {
+        input_flags |= LineRequestFlags::ACTIVE_LOW
+    };
+    for ((vend, stocked), cam) in vend
+        .into_iter()
+        .zip(stocked)
+        .zip(cam.chain(std::iter::repeat(None)))
+    {
+        let pins = GpioPins {
+            vend: lookup_pin(settings, "BUB_VEND_PINS", vend)?,
+            stocked: lookup_pin(settings, "BUB_STOCKED_PINS", stocked)?,
+            active_low,
+            cam: cam
+                .map(|cam| lookup_pin(settings, "BUB_CAM_PINS", &cam))
+                .transpose()?,
+            fault: fault
+                .next()
+                .flatten()
+                .map(|fault| lookup_pin(settings, "BUB_MOTOR_FAULT_PINS", &fault))
+                .transpose()?,
+        };
+        // Its lines are already ours, so requesting them again would fail
+        if let Some(kept) = previous
+            .iter()
+            .find(|slot| slot.gpio_pins().as_ref() == Some(&pins))
+        {
+            slots.push(kept.clone());
+            continue;
+        }
+        // Requesting an output sets its value, so carry over whatever the
+        // motor was doing for startup_check to find
+        let was_on = pins
+            .vend
+            .request(LineRequestFlags::empty(), 0, "bubbler-peek")
+            .and_then(|peek| peek.get_value())
+            .unwrap_or(0);
+        let vend = pins
+            .vend
+            .request(LineRequestFlags::OUTPUT, was_on, "bubbler-vend")
+            .map_err(gpio_error("BUB_VEND_PINS", vend))?;
+        let stocked = pins
+            .stocked
+            .events(
+                input_flags,
+                EventRequestFlags::BOTH_EDGES,
+                "bubbler-stocked",
+            )
+            .map_err(gpio_error("BUB_STOCKED_PINS", stocked))?;
+        slots.push(Arc::new(SlotConfig::GPIO {
+            vend,
+            stocked,
+            cam: pins.cam,
+            fault: pins.fault,
+        }));
+    }
+    Ok(slots)
+}
+
+#[cfg(not(feature = "gpio"))]
+fn gpio_slots(
+    settings: &Settings,
+    _previous: &[Arc<SlotConfig>],
+) -> Result<Vec<Arc<SlotConfig>>, ConfigError> {
+    if settings.var("BUB_VEND_PINS").is_ok() {
+        return Err(ConfigError::FeatureNotEnabled {
+            feature: "gpio",
+            variable: "BUB_VEND_PINS",
+        });
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(not(feature = "gpio"))]
+fn latch(settings: &Settings) -> Result<(), ConfigError> {
+    if settings.var("BUB_LATCH_PIN").is_ok() {
+        return Err(ConfigError::FeatureNotEnabled {
+            feature: "gpio",
+            variable: "BUB_LATCH_PIN",
+        });
+    }
+    Ok(())
+}
+
+/// `BUB_MOCK_SLOTS` pretend slots when `BUB_MOCK=1`, or always when
+/// there's no real hardware support built in. `None` means use the hardware.
+#[cfg(any(test, feature = "mock"))]
+fn mock_slots(settings: &Settings) -> Result<Option<Vec<Arc<SlotConfig>>>, ConfigError> {
+    let hardware = cfg!(any(feature = "owfs", feature = "gpio"));
+    if hardware && settings.var("BUB_MOCK").unwrap_or_default() != "1" {
+        return Ok(None);
+    }
+    let count: usize = setting_number(settings, "BUB_MOCK_SLOTS", 4)?;
+    let stock = setting_number(settings, "BUB_MOCK_STOCK", 5)?;
+    let cam_time = Duration::from_millis(setting_number(settings, "BUB_MOCK_CAM_MS", 1500)?);
+    Ok(Some(
+        (0..count)
+            .map(|number| Arc::new(SlotConfig::Mock(MockSlot::new(number, stock, cam_time))))
+            .collect(),
+    ))
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+fn mock_slots(settings: &Settings) -> Result<Option<Vec<Arc<SlotConfig>>>, ConfigError> {
+    if settings.var("BUB_MOCK").unwrap_or_default() == "1" {
+        return Err(ConfigError::FeatureNotEnabled {
+            feature: "mock",
+            variable: "BUB_MOCK",
+        });
+    }
+    Ok(None)
+}
+
+/// `BUB_FAULT_SLOTS`, only read when `BUB_SIMULATE=1` so a stray variable
+/// can't break a real machine
+fn faults(settings: &Settings) -> Result<Vec<Fault>, ConfigError> {
+    if settings.var("BUB_SIMULATE").unwrap_or_default() != "1" {
+        return Ok(Vec::new());
+    }
+    let specs = settings.var("BUB_FAULT_SLOTS").unwrap_or_default();
+    specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| {
+            let invalid = || ConfigError::InvalidFault(spec.to_string());
+            let (slot, operation) = match spec.split_once(':') {
+                Some((slot, "motor_on")) => (slot, FaultOperation::MotorOn),
+                Some((slot, "motor_off")) => (slot, FaultOperation::MotorOff),
+                Some(_) => return Err(invalid()),
+                None => (spec, FaultOperation::Any),
+            };
+            Ok(Fault {
+                slot: slot.parse().map_err(|_| invalid())?,
+                operation,
+            })
+        })
+        .collect()
+}
+
+fn rt_policy(settings: &Settings) -> Result<RtPolicy, ConfigError> {
+    match settings.var("BUB_RT_POLICY") {
+        Err(_) => Ok(RtPolicy::default()),
+        Ok(policy) => match policy.trim() {
+            "" | "fifo" => Ok(RtPolicy::Fifo),
+            "rr" => Ok(RtPolicy::RoundRobin),
+            _ => Err(ConfigError::InvalidRtPolicy(policy)),
+        },
+    }
+}
+
+/// Slot indices from `BUB_SLOT_STOCKED_INVERT`, which have to be below
+/// `slot_count`
+fn stocked_invert(settings: &Settings, slot_count: usize) -> Result<Vec<usize>, ConfigError> {
+    let specs = settings.var("BUB_SLOT_STOCKED_INVERT").unwrap_or_default();
+    specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| {
+            spec.parse()
+                .ok()
+                .filter(|slot| *slot < slot_count)
+                .ok_or_else(|| ConfigError::InvalidStockedInvert(spec.to_string()))
+        })
+        .collect()
+}
+
+fn trusted_proxies(settings: &Settings) -> Result<TrustedProxies, ConfigError> {
+    let specs = settings.var("BUB_TRUSTED_PROXIES").unwrap_or_default();
+    specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| {
+            spec.parse::<IpNet>()
+                .or_else(|_| spec.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| ConfigError::InvalidTrustedProxy(spec.to_string()))
+        })
+        .collect::<Result<_, _>>()
+        .map(TrustedProxies)
+}
+
+impl ConfigData {
+    pub fn new(settings: &Settings) -> Result<ConfigData, ConfigError> {
+        Self::build(settings, None)
+    }
+
+    /// Like `new`, but keeps `previous`'s slots and latch wherever their
+    /// hardware hasn't changed, open lines and all, so they can be built
+    /// while `previous` is still in use
+    pub fn reload(settings: &Settings, previous: &ConfigData) -> Result<ConfigData, ConfigError> {
+        Self::build(settings, Some(previous))
+    }
+
+    fn build(
+        settings: &Settings,
+        previous: Option<&ConfigData>,
+    ) -> Result<ConfigData, ConfigError> {
+        let previous_slots = previous.map_or(&[][..], |previous| previous.slots.as_slice());
+        let mock = mock_slots(settings)?;
+        let mocked = mock.is_some();
+        let slots = match mock {
+            Some(slots) => slots,
+            None => {
+                // DS2408 channels go after any plain OWFS slots, and stand in
+                // for GPIO pins if there aren't any
+                let owfs_gpio = owfs_gpio_slots(settings)?;
+                let mut slots = match slot_addresses(settings)? {
+                    Some(addresses) => owfs_slots(addresses)?,
+                    None if !owfs_gpio.is_empty() => Vec::new(),
+                    None => gpio_slots(settings, previous_slots)?,
+                };
+                slots.extend(owfs_gpio);
+                slots
+            }
+        };
+        let slots: Vec<_> = slots
+            .into_iter()
+            .map(|slot| {
+                previous_slots
+                    .iter()
+                    .find(|kept| Arc::ptr_eq(kept, &slot) || kept.is_hardware_equivalent(&slot))
+                    .cloned()
+                    .unwrap_or(slot)
+            })
+            .collect();
+        if previous.is_some() {
+            let (kept, fresh): (Vec<_>, Vec<_>) = (0..slots.len()).partition(|&number| {
+                previous_slots
+                    .iter()
+                    .any(|kept| Arc::ptr_eq(kept, &slots[number]))
+            });
+            info!(
+                "Kept slots {:?} as they were, re-initialized slots {:?}",
+                kept, fresh
+            );
+        }
+        let temperature_id = settings
+            .var("BUB_TEMP_ADDRESS")
+            .ok()
+            .filter(|id| !id.is_empty());
+        // The temperature sensor hangs off the 1-wire bus too
+        if cfg!(not(feature = "owfs")) && temperature_id.is_some() {
+            return Err(ConfigError::FeatureNotEnabled {
+                feature: "owfs",
+                variable: "BUB_TEMP_ADDRESS",
+            });
+        }
+        // Mock machines always have a sensor, so /temperature has something
+        let temperature_id = temperature_id.or_else(|| mocked.then(|| "mock".to_string()));
+        #[cfg(feature = "gpio")]
+        let latch = Latch::from_settings_keeping(
+            settings,
+            previous.and_then(|previous| previous.latch.as_ref()),
+        )?;
+        #[cfg(not(feature = "gpio"))]
+        latch(settings)?;
+        let drop_delay = drop_delay(settings)?;
+        let stocked_invert = stocked_invert(settings, slots.len())?;
+        Ok(ConfigData {
+            machine_id: machine_id(settings),
+            temperature_id,
+            slots,
+            #[cfg(feature = "gpio")]
+            latch,
+            drop_delay,
+            rt_policy: rt_policy(settings)?,
+            allow_remote_reboot: settings.var("BUB_ALLOW_REMOTE_REBOOT").unwrap_or_default() == "1",
+            cam_history_size: setting_number(settings, "BUB_CAM_HISTORY_SIZE", 20)?,
+            stock_poll_ms: setting_number(settings, "BUB_STOCK_POLL_MS", 1000)?,
+            stocked_debounce_ms: setting_number(settings, "BUB_STOCKED_DEBOUNCE_MS", 50)?,
+            max_count_per_drop: setting_number(settings, "BUB_MAX_COUNT_PER_DROP", 5)?,
+            multi_drop_interval_ms: setting_number(settings, "BUB_MULTI_DROP_INTERVAL_MS", 1000)?,
+            metadata_max_bytes: setting_number(settings, "BUB_METADATA_MAX_BYTES", 1024)?,
+            #[cfg(feature = "owfs")]
+            owfs_verify_retry: setting_number(settings, "BUB_OWFS_VERIFY_RETRY", 3)?,
+            #[cfg(feature = "owfs")]
+            temp_unit_millidegrees: settings
+                .var("BUB_TEMP_UNIT_MILLIDEGREES")
+                .unwrap_or_default()
+                == "1",
+            temp_error_threshold: setting_number(settings, "BUB_TEMP_ERROR_THRESHOLD", 3)?,
+            api_key: settings
+                .var("BUB_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            enable_debug: settings.var("BUB_ENABLE_DEBUG").unwrap_or_default() == "1",
+            #[cfg(feature = "pprof")]
+            enable_pprof: settings.var("BUB_ENABLE_PPROF").unwrap_or_default() == "1",
+            trusted_proxies: trusted_proxies(settings)?,
+            faults: faults(settings)?,
+            stocked_invert,
+            #[cfg(any(test, feature = "mock"))]
+            mock: mocked,
+        })
+    }
+
+    /// The slot at 0-based `index`, `None` past the last one
+    pub fn get_slot(&self, index: usize) -> Option<&SlotConfig> {
+        self.slots.get(index).map(Arc::as_ref)
+    }
+
+    /// The simulated fault for `slot` turning its motor to `state`, `None`
+    /// when there isn't one
+    pub fn injected_fault(&self, slot: usize, state: bool) -> Option<&Fault> {
+        self.faults
+            .iter()
+            .find(|fault| fault.applies_to(slot, state))
+    }
+
+    /// Whether `slot`'s stocked switch has to be flipped after reading it
+    pub fn stocked_inverted(&self, slot: usize) -> bool {
+        self.stocked_invert.contains(&slot)
+    }
+
+    /// A copy without any slots or latch, to stand in while a reload has the
+    /// hardware let go of
+    pub fn unwired(&self) -> ConfigData {
+        ConfigData {
+            slots: Vec::new(),
+            #[cfg(feature = "gpio")]
+            latch: None,
+            ..self.clone()
+        }
+    }
+
+    /// Whether anything besides this config has one of its slots or its
+    /// latch, which keeps their lines requested even once it's dropped
+    pub fn hardware_shared(&self) -> bool {
+        #[cfg(feature = "gpio")]
+        let latch_shared = self
+            .latch
+            .as_ref()
+            .is_some_and(|latch| Arc::strong_count(latch) > 1);
+        #[cfg(not(feature = "gpio"))]
+        let latch_shared = false;
+        latch_shared || self.slots.iter().any(|slot| Arc::strong_count(slot) > 1)
+    }
+
+    pub fn latch_healthy(&self) -> Option<bool> {
+        #[cfg(feature = "gpio")]
+        return self.latch.as_deref().map(Latch::is_healthy);
+        #[cfg(not(feature = "gpio"))]
+        return None;
+    }
+}
+
+impl Display for ConfigData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Machine ID: {}", self.machine_id)?;
+        writeln!(f, "Slots: {}", self.slots.len())?;
+        for (index, slot) in self.slots.iter().enumerate() {
+            writeln!(f, "  {}: {}", index, slot)?;
+        }
+        match &self.temperature_id {
+            None => writeln!(f, "Temperature sensor: none")?,
+            Some(id) => writeln!(f, "Temperature sensor: {}", id)?,
+        }
+        #[cfg(feature = "gpio")]
+        writeln!(
+            f,
+            "Latch: {}",
+            if self.latch.is_some() { "yes" } else { "no" }
+        )?;
+        writeln!(f, "Drop delay: {}ms", self.drop_delay.as_millis())?;
+        writeln!(f, "Real-time policy: {}", self.rt_policy)?;
+        writeln!(f, "Remote reboot allowed: {}", self.allow_remote_reboot)?;
+        if !self.trusted_proxies.0.is_empty() {
+            let proxies: Vec<String> = self
+                .trusted_proxies
+                .0
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(f, "Trusted proxies: {}", proxies.join(", "))?;
+        }
+        writeln!(
+            f,
+            "API key: {}",
+            if self.api_key.is_some() {
+                "set"
+            } else {
+                "none"
+            }
+        )?;
+        if !self.stocked_invert.is_empty() {
+            let slots: Vec<String> = self
+                .stocked_invert
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(f, "Inverted stocked switches: {}", slots.join(", "))?;
+        }
+        for fault in &self.faults {
+            writeln!(
+                f,
+                "Simulated fault: slot {} ({})",
+                fault.slot, fault.operation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct AppData {
+    /// Swapped out whole rather than locked, so reading it never waits
+    pub config: ArcSwap<ConfigData>,
+    /// Held for the whole of a drop, so only one motor runs at a time
+    pub drop_lock: Mutex<()>,
+    pub history: Mutex<History>,
+    pub stock_events: Mutex<StockEventLog>,
+    pub events: broadcast::Sender<MachineEvent>,
+    pub offline: AtomicBool,
+    /// Set while a config reload has the slots, so drops wait it out rather
+    /// than catch the machine between configs
+    pub reloading: AtomicBool,
+    /// Set while a drop has the motor going
+    pub drop_in_progress: AtomicBool,
+    /// Temperature reads that have failed since the last good one
+    pub temp_failures: AtomicU32,
+    pub dry_run: bool,
+    /// Cancelled by an emergency stop, which interrupts any drop waiting on
+    /// its cam. Replaced when the machine comes back online.
+    pub drop_cancel: StdMutex<CancellationToken>,
+    /// What the current config was built from, to rebuild it on a reload
+    pub settings: Mutex<Settings>,
+    /// `None` until the server has started them
+    pub monitors: Mutex<Option<Monitors>>,
+    /// Whether drops can get real-time priority under the `BUB_RT_POLICY`
+    /// the server started with, `None` if we couldn't tell
+    pub realtime_capable: Option<bool>,
+}
+
+impl AppData {
+    /// Online, with nothing dropped yet. The monitors are left for the
+    /// caller to start once it has this in a `web::Data`.
+    pub fn new(config: ConfigData, settings: Settings, dry_run: bool) -> Self {
+        AppData {
+            realtime_capable: probe_realtime(config.rt_policy),
+            config: ArcSwap::from_pointee(config),
+            drop_lock: Mutex::new(()),
+            history: Mutex::new(History::default()),
+            stock_events: Mutex::new(StockEventLog::default()),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            offline: AtomicBool::new(false),
+            reloading: AtomicBool::new(false),
+            drop_in_progress: AtomicBool::new(false),
+            temp_failures: AtomicU32::new(0),
+            dry_run,
+            drop_cancel: StdMutex::new(CancellationToken::new()),
+            settings: Mutex::new(settings),
+            monitors: Mutex::new(None),
+        }
+    }
+
+    /// Tells any websocket clients about `event`
+    pub fn emit(&self, event: MachineEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    /// The config as it is right now. Later changes don't show up in the
+    /// `Arc` this hands back, so hang on to it for a consistent view.
+    pub fn config(&self) -> Arc<ConfigData> {
+        self.config.load_full()
+    }
+
+    /// How many slots the machine has
+    pub fn slot_count(&self) -> usize {
+        self.config.load().slots.len()
+    }
+
+    /// Whether `index` is a valid 0-based slot index
+    pub fn has_slot(&self, index: usize) -> bool {
+        index < self.slot_count()
+    }
+
+    /// The token the next drop should watch for an emergency stop
+    pub fn drop_cancel(&self) -> CancellationToken {
+        self.drop_cancel.lock().unwrap().clone()
+    }
+
+    /// Lets drops run again after an emergency stop
+    pub fn reset_drop_cancel(&self) {
+        let mut cancel = self.drop_cancel.lock().unwrap();
+        if cancel.is_cancelled() {
+            *cancel = CancellationToken::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_restocks_mock_slots() {
+        let settings = Settings::from_vars(&[("BUB_MOCK", "1"), ("BUB_MOCK_SLOTS", "2")]);
+        let old = ConfigData::new(&settings).expect("mock config is valid");
+        let new = ConfigData::reload(&settings, &old).expect("mock config is valid");
+        assert_eq!(new.slots.len(), 2);
+        for (old, new) in old.slots.iter().zip(&new.slots) {
+            assert!(!Arc::ptr_eq(old, new));
+        }
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn chip_path_template_comes_from_settings() {
+        let settings = Settings::from_vars(&[("BUB_GPIO_CHIP_PATH_TEMPLATE", "/dev/gpio/chip{}")]);
+        assert_eq!(chip_path(&settings, 2), "/dev/gpio/chip2");
+    }
+
+    #[cfg(feature = "owfs")]
+    #[test]
+    fn reload_keeps_unchanged_owfs_slots() {
+        let old = ConfigData::new(&Settings::from_vars(&[(
+            "BUB_SLOT_ADDRESSES",
+            "05.A1B2C3000000,05.D4E5F6000000",
+        )]))
+        .expect("OWFS config is valid");
+        let new = ConfigData::reload(
+            &Settings::from_vars(&[("BUB_SLOT_ADDRESSES", "05.A1B2C3000000,05.0A0B0C000000")]),
+            &old,
+        )
+        .expect("OWFS config is valid");
+        assert!(Arc::ptr_eq(&old.slots[0], &new.slots[0]));
+        assert!(!Arc::ptr_eq(&old.slots[1], &new.slots[1]));
+        assert_eq!(new.slots[1].to_string(), "05.0A0B0C000000");
+    }
+}
@@ -0,0 +1,264 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::ConfigError;
+
+/// Where `ConfigData::new` gets its settings: the `--config-file` first, then
+/// the environment for anything the file doesn't cover
+#[derive(Clone, Default)]
+pub struct Settings {
+    path: Option<PathBuf>,
+    /// The file's settings, keyed by the variable they stand in for. `None`
+    /// hides the variable, so slots from the file don't mix with slots from
+    /// the environment.
+    file: HashMap<&'static str, Option<String>>,
+}
+
+impl Settings {
+    /// Only the environment when there's no `path`
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Settings::default()),
+        };
+        let invalid = |reason: String| ConfigError::ConfigFile {
+            path: path.display().to_string(),
+            reason,
+        };
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let file: ConfigFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        Ok(Settings {
+            path: Some(path.to_path_buf()),
+            file: file.variables().map_err(invalid)?,
+        })
+    }
+
+    /// Reads the same file again, for picking up changes to it
+    pub fn reload(&self) -> Result<Self, ConfigError> {
+        Self::load(self.path.as_deref())
+    }
+
+    /// As if `variables` came from a config file, so tests don't have to
+    /// touch the environment
+    #[cfg(test)]
+    pub fn from_vars(variables: &[(&'static str, &str)]) -> Self {
+        Settings {
+            path: None,
+            file: variables
+                .iter()
+                .map(|(variable, value)| (*variable, Some(value.to_string())))
+                .collect(),
+        }
+    }
+
+    /// Like `env::var`, but the config file wins
+    pub fn var(&self, variable: &str) -> Result<String, VarError> {
+        match self.file.get(variable) {
+            Some(Some(value)) => Ok(value.clone()),
+            Some(None) => Err(VarError::NotPresent),
+            None => env::var(variable),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    drop_delay_ms: Option<u64>,
+    temperature_address: Option<String>,
+    temp_unit_millidegrees: Option<bool>,
+    /// For the GPIO stocked switches
+    active_low: Option<bool>,
+    /// Where the gpiochips are, `{}` being the chip number
+    gpio_chip_path_template: Option<String>,
+    latch: Option<LatchTable>,
+    #[serde(default)]
+    slots: Vec<SlotTable>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LatchTable {
+    pin: Pin,
+    watchdog_ms: Option<u64>,
+}
+
+/// One `[[slots]]` table. Which keys are set decides what kind of slot it is.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlotTable {
+    /// OWFS device ID
+    owfs: Option<String>,
+    /// A second OWFS device driven alongside `owfs`
+    backup: Option<String>,
+    /// DS2408 channel, as deviceid:channel
+    owfs_gpio: Option<String>,
+    vend: Option<Pin>,
+    stocked: Option<Pin>,
+    cam: Option<Pin>,
+    fault: Option<Pin>,
+}
+
+/// `17`, or `"17:1"` for a pin on another chip
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Pin {
+    Offset(u32),
+    Spec(String),
+}
+
+impl Display for Pin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Offset(offset) => write!(f, "{}", offset),
+            Self::Spec(spec) => write!(f, "{}", spec),
+        }
+    }
+}
+
+/// Every variable the slots are configured through
+const SLOT_VARIABLES: [&str; 7] = [
+    "BUB_SLOT_ADDRESSES",
+    "BUB_SLOT_ADDRESSES_FILE",
+    "BUB_OWFS_GPIO_DEVICES",
+    "BUB_VEND_PINS",
+    "BUB_STOCKED_PINS",
+    "BUB_CAM_PINS",
+    "BUB_MOTOR_FAULT_PINS",
+];
+
+fn flag(set: bool) -> String {
+    String::from(if set { "1" } else { "0" })
+}
+
+/// Joins optional pins, leaving an empty entry for slots without one
+fn pin_list(pins: &[Option<Pin>]) -> Option<String> {
+    if pins.iter().all(Option::is_none) {
+        return None;
+    }
+    let pins: Vec<String> = pins
+        .iter()
+        .map(|pin| pin.as_ref().map(ToString::to_string).unwrap_or_default())
+        .collect();
+    Some(pins.join(","))
+}
+
+impl ConfigFile {
+    /// The file as the variables it replaces
+    fn variables(self) -> Result<HashMap<&'static str, Option<String>>, String> {
+        let mut variables = HashMap::new();
+        let mut set = |variable: &'static str, value: Option<String>| {
+            if value.is_some() {
+                variables.insert(variable, value);
+            }
+        };
+        set(
+            "BUB_DROP_DELAY",
+            self.drop_delay_ms.map(|ms| ms.to_string()),
+        );
+        set("BUB_TEMP_ADDRESS", self.temperature_address);
+        set(
+            "BUB_TEMP_UNIT_MILLIDEGREES",
+            self.temp_unit_millidegrees.map(flag),
+        );
+        set("BUB_ACTIVE_LOW", self.active_low.map(flag));
+        set("BUB_GPIO_CHIP_PATH_TEMPLATE", self.gpio_chip_path_template);
+        if let Some(latch) = self.latch {
+            set("BUB_LATCH_PIN", Some(latch.pin.to_string()));
+            set(
+                "BUB_LATCH_WATCHDOG_MS",
+                latch.watchdog_ms.map(|ms| ms.to_string()),
+            );
+        }
+        if self.slots.is_empty() {
+            return Ok(variables);
+        }
+
+        let mut owfs = Vec::new();
+        let mut owfs_gpio = Vec::new();
+        let mut vend = Vec::new();
+        let mut stocked = Vec::new();
+        let mut cam = Vec::new();
+        let mut fault = Vec::new();
+        for (index, slot) in self.slots.into_iter().enumerate() {
+            match slot {
+                SlotTable {
+                    owfs: Some(id),
+                    backup,
+                    owfs_gpio: None,
+                    vend: None,
+                    stocked: None,
+                    cam: None,
+                    fault: None,
+                } => {
+                    // Same as BUB_OWFS_GPIO_DEVICES, DS2408 slots are numbered
+                    // after the rest
+                    if !owfs_gpio.is_empty() {
+                        return Err(format!(
+                            "slot {} has to come before the owfs_gpio slots",
+                            index
+                        ));
+                    }
+                    owfs.push(match backup {
+                        Some(backup) => format!("primary:{}|backup:{}", id, backup),
+                        None => id,
+                    });
+                }
+                SlotTable {
+                    owfs: None,
+                    backup: None,
+                    owfs_gpio: Some(spec),
+                    vend: None,
+                    stocked: None,
+                    cam: None,
+                    fault: None,
+                } => owfs_gpio.push(spec),
+                SlotTable {
+                    owfs: None,
+                    backup: None,
+                    owfs_gpio: None,
+                    vend: Some(vend_pin),
+                    stocked: Some(stocked_pin),
+                    cam: cam_pin,
+                    fault: fault_pin,
+                } => {
+                    vend.push(vend_pin.to_string());
+                    stocked.push(stocked_pin.to_string());
+                    cam.push(cam_pin);
+                    fault.push(fault_pin);
+                }
+                _ => {
+                    return Err(format!(
+                        "slot {} needs owfs, owfs_gpio, or vend and stocked, and nothing else",
+                        index
+                    ))
+                }
+            }
+        }
+        if !vend.is_empty() && (!owfs.is_empty() || !owfs_gpio.is_empty()) {
+            return Err("GPIO slots can't be mixed with OWFS ones".to_string());
+        }
+
+        // The file's slots replace the environment's, rather than adding to them
+        for variable in SLOT_VARIABLES {
+            variables.insert(variable, None);
+        }
+        let mut set = |variable: &'static str, value: Option<String>| {
+            if value.is_some() {
+                variables.insert(variable, value);
+            }
+        };
+        let join = |list: Vec<String>| Some(list.join(",")).filter(|list| !list.is_empty());
+        set("BUB_SLOT_ADDRESSES", join(owfs));
+        set("BUB_OWFS_GPIO_DEVICES", join(owfs_gpio));
+        set("BUB_VEND_PINS", join(vend));
+        set("BUB_STOCKED_PINS", join(stocked));
+        set("BUB_CAM_PINS", pin_list(&cam));
+        set("BUB_MOTOR_FAULT_PINS", pin_list(&fault));
+        Ok(variables)
+    }
+}
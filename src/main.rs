@@ -1,25 +1,216 @@
-use actix_web::{web, App, HttpServer};
-use tokio::sync::Mutex;
+// Covers `expect` as well, clippy has no separate expect_in_result
+#![deny(clippy::unwrap_in_result)]
 
+use actix_web::http::header::{CACHE_CONTROL, PRAGMA};
+use actix_web::http::KeepAlive;
+use actix_web::{middleware, web, App, HttpServer};
+use futures::future;
+use std::env;
+use std::time::Duration;
+#[cfg(feature = "sentry")]
+use tracing::warn;
+use tracing::{error, info};
+#[cfg(feature = "sentry")]
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[cfg(not(any(feature = "owfs", feature = "gpio", feature = "mock")))]
+compile_error!("bubbler needs at least one of the `owfs`, `gpio` or `mock` features");
+
+pub mod cli;
+pub mod config;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod routes;
 pub mod scheduler;
-use routes::config::{AppData, ConfigData};
+use clap::Parser;
+use cli::Cli;
+use config::{AppData, ConfigData, Settings};
+use routes::client_ip::resolve_client_ip;
+use routes::poller::Monitors;
+use routes::slot_stats::SlotStatsRegistry;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let config_data = ConfigData::new();
-    let config_data = web::Data::new(AppData {
-        config: Mutex::new(config_data),
+    // Everything else logs through tracing, so it has to be set up before
+    // anything that can fail. Only the arguments come first, for the level.
+    let cli = Cli::parse();
+
+    // Also picks up `log` records, which is what actix's Logger emits
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .finish();
+    // Errors become Sentry events and everything else breadcrumbs. Does
+    // nothing until init_sentry sets up a client.
+    #[cfg(feature = "sentry")]
+    let subscriber = subscriber.with(sentry_tracing::layer());
+    subscriber.init();
+
+    let loaded = Settings::load(cli.config.as_deref())
+        .and_then(|settings| ConfigData::new(&settings).map(|config| (settings, config)));
+    let (settings, config_data) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if cli.validate_config {
+        print!("Configuration is valid\n{}", config_data);
+        return Ok(());
+    }
+    #[cfg(feature = "sentry")]
+    let _sentry = init_sentry(&config_data);
+    if cli.dry_run {
+        info!("Dry run: drops won't actuate any motors");
+    } else {
+        routes::machine::startup_check(&config_data);
+    }
+    let trusted_proxies = web::Data::new(config_data.trusted_proxies.clone());
+    let config_data = web::Data::new(AppData::new(config_data, settings, cli.dry_run));
+
+    let slot_stats = web::Data::new(SlotStatsRegistry::default());
+    *config_data.monitors.lock().await =
+        Some(Monitors::start(config_data.clone(), slot_stats.clone()));
+
+    let access_log_format = env::var("BUB_ACCESS_LOG_FORMAT")
+        .unwrap_or_else(|_| "%{client_ip}xi %r %s %b %D ms %{X-Request-Id}i".to_string());
+
+    // With more than one port, the first only gets the routes drink clients
+    // need and the rest get everything
+    let make_server = |management: bool| {
+        let config_data = config_data.clone();
+        let slot_stats = slot_stats.clone();
+        let access_log_format = access_log_format.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let max_body = cli.max_request_body_bytes;
+        let server = HttpServer::new(move || {
+            let log_proxies = trusted_proxies.clone();
+            App::new()
+                .wrap(middleware::from_fn(resolve_client_ip))
+                .wrap(
+                    middleware::Logger::new(&access_log_format).custom_request_replace(
+                        "client_ip",
+                        move |req| {
+                            log_proxies
+                                .client_ip_for(req)
+                                .map(|ip| ip.to_string())
+                                .unwrap_or_else(|| "-".to_string())
+                        },
+                    ),
+                )
+                // Everything we serve is live hardware state, don't let anyone cache it
+                .wrap(
+                    middleware::DefaultHeaders::new()
+                        .add((CACHE_CONTROL, "no-store"))
+                        .add((PRAGMA, "no-cache")),
+                )
+                .app_data(config_data.clone())
+                .app_data(slot_stats.clone())
+                .app_data(trusted_proxies.clone())
+                .app_data(web::JsonConfig::default().limit(max_body))
+                .app_data(web::FormConfig::default().limit(max_body))
+                .app_data(web::PayloadConfig::default().limit(max_body))
+                .configure(public_routes)
+                .configure(|cfg| {
+                    if management {
+                        management_routes(cfg)
+                    }
+                })
+        });
+        let server = match cli.keepalive_seconds {
+            0 => server.keep_alive(KeepAlive::Disabled),
+            seconds => server.keep_alive(Duration::from_secs(seconds)),
+        };
+        match cli.listen_backlog {
+            Some(backlog) => server.backlog(backlog),
+            None => server,
+        }
+    };
+
+    let (public_port, management_ports) = cli.port.split_first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "No port to listen on")
+    })?;
+    if management_ports.is_empty() {
+        return make_server(true)
+            .bind((cli.bind, *public_port))?
+            .run()
+            .await;
+    }
+    let public = make_server(false).bind((cli.bind, *public_port))?;
+    let mut management = make_server(true);
+    for port in management_ports {
+        management = management.bind((cli.bind, *port))?;
+    }
+    info!(
+        "Serving drink routes on port {} and everything on {:?}",
+        public_port, management_ports
+    );
+    future::try_join(public.run(), management.run()).await?;
+    Ok(())
+}
+
+/// Reports panics and error logs to `BUB_SENTRY_DSN`, if it's set. Events
+/// stop being sent once the guard is dropped, so hang on to it.
+#[cfg(feature = "sentry")]
+fn init_sentry(config: &ConfigData) -> Option<sentry::ClientInitGuard> {
+    let dsn = env::var("BUB_SENTRY_DSN")
+        .ok()
+        .filter(|dsn| !dsn.is_empty())?;
+    let dsn = match dsn.parse::<sentry::types::Dsn>() {
+        Ok(dsn) => dsn,
+        Err(err) => {
+            warn!(
+                "Not reporting to Sentry, BUB_SENTRY_DSN is invalid: {}",
+                err
+            );
+            return None;
+        }
+    };
+    // Panics get reported by the default integrations
+    let mut options = sentry::ClientOptions::new();
+    options.dsn = Some(dsn);
+    options.release = sentry::release_name!();
+    let guard = sentry::init(options);
+    sentry::configure_scope(|scope| {
+        scope.set_tag("machine_id", &config.machine_id);
+        scope.set_tag("slot_count", config.slots.len());
+        scope.set_tag("firmware_version", env!("CARGO_PKG_VERSION"));
     });
+    info!("Reporting crashes to Sentry");
+    Some(guard)
+}
+
+/// What drink clients need
+fn public_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(routes::drop)
+        .service(routes::health)
+        .service(routes::get_slots);
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(config_data.clone())
-            .service(routes::drop)
-            .service(routes::health)
-            .service(routes::get_slots)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+/// Everything else, for operators and monitoring
+fn management_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(routes::get_drops)
+        .service(routes::clear_drops)
+        .service(routes::get_stock_events)
+        .service(routes::get_temperature)
+        .service(routes::get_slot)
+        .service(routes::stock_override)
+        .service(routes::verify_slot)
+        .service(routes::update_slot_meta)
+        .service(routes::cam_history)
+        .service(routes::slot_stocked)
+        .service(routes::set_offline)
+        .service(routes::set_online)
+        .service(routes::emergency_stop)
+        .service(routes::reload_config)
+        .service(routes::machine_state)
+        .service(routes::reboot)
+        .service(routes::diagnostics)
+        .service(routes::ws::ws)
+        .configure(routes::debug::configure)
+        .service(routes::openapi_json)
+        .service(routes::swagger_ui)
+        .service(routes::swagger_ui_css)
+        .service(routes::swagger_ui_bundle);
 }
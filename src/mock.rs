@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the pretend fridge takes to drift from coldest to warmest and
+/// back
+const TEMPERATURE_PERIOD_SECS: u64 = 300;
+
+/// A slot with no hardware behind it, for running the server on a machine
+/// without any. It runs out after `BUB_MOCK_STOCK` drops, and a reload fills
+/// it back up.
+pub struct MockSlot {
+    pub number: usize,
+    stock: AtomicU32,
+    motor: AtomicBool,
+    /// How long the cam takes to go round once the motor's on
+    pub cam_time: Duration,
+}
+
+impl MockSlot {
+    pub fn new(number: usize, stock: u32, cam_time: Duration) -> Self {
+        MockSlot {
+            number,
+            stock: AtomicU32::new(stock),
+            motor: AtomicBool::new(false),
+            cam_time,
+        }
+    }
+
+    pub fn is_stocked(&self) -> bool {
+        self.stock.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.motor.load(Ordering::SeqCst)
+    }
+
+    /// A drink falls out whenever a running motor gets turned off
+    pub fn set_motor(&self, on: bool) {
+        let was_on = self.motor.swap(on, Ordering::SeqCst);
+        if was_on && !on {
+            // Nothing to drop once it's empty
+            let _ = self
+                .stock
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |stock| {
+                    stock.checked_sub(1)
+                });
+        }
+    }
+}
+
+/// Somewhere between 3 and 5°C, wandering so graphs have something to show
+pub fn temperature() -> f32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        % TEMPERATURE_PERIOD_SECS;
+    let phase = secs as f32 / TEMPERATURE_PERIOD_SECS as f32;
+    4.0 + (phase * std::f32::consts::TAU).sin()
+}
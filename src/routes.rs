@@ -1,87 +1,1808 @@
+use actix_web::http::header::{
+    ETag, EntityTag, HttpDate, IfNoneMatch, LastModified, ACCEPT, AUTHORIZATION, RETRY_AFTER,
+};
 use actix_web::http::StatusCode;
-use actix_web::{get, post, web, HttpResponse, Responder};
-use serde::{Deserialize, Serialize};
+use actix_web::{
+    delete, get, patch, post, put, web, Either, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+use utoipa::openapi::Deprecated;
+use utoipa::{Modify, OpenApi, ToSchema};
+use validator::{Validate, ValidateArgs, ValidationError};
 
-pub mod config;
+pub mod client_ip;
+pub mod debug;
+pub mod events;
+pub mod history;
 pub mod machine;
-use config::AppData;
-use machine::DropError;
+pub mod poller;
+pub mod slot_stats;
+#[cfg(test)]
+mod tests;
+pub mod ws;
+use crate::config::{AppData, ConfigData, Settings, MAX_DROP_DELAY_MS};
+use crate::scheduler::{RealtimeGuard, REALTIME_PRIORITY};
+use client_ip::ClientIp;
+use events::MachineEvent;
+use history::{
+    push_bounded, CamPulseRecord, CamPulseResult, DropRecord, DropStats, StockEvent,
+    TemperatureReading,
+};
+use machine::drop::{DropError, DropMethod, DropOptions};
+use machine::{status, temperature};
+use poller::Monitors;
+use slot_stats::{SlotState, SlotStatsRegistry, StockedCheck};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 struct HealthReport {
-    slots: Vec<String>,
-    temp: f32,
+    slots: Vec<status::LegacySlotEntry>,
+    /// Fahrenheit, `null` without a sensor or when it keeps failing
+    temp: Option<f32>,
+    offline: bool,
+    /// Read before waiting for the drop to finish, so it can be `true`
+    drop_in_progress: bool,
 }
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct SlotReport {
-    slots: Vec<machine::SlotStatus>,
-    temp: f32,
+    /// Bumped when the envelope changes shape
+    api_version: &'static str,
+    machine_id: String,
+    total_slots: usize,
+    stocked_slots_count: usize,
+    /// Slots whose stocked switch couldn't be read
+    unreadable_slots_count: usize,
+    generated_at: String,
+    slots: Vec<status::SlotStatus>,
+    /// `null` without a sensor, or when it keeps failing
+    temp: Option<f32>,
+    drop_in_progress: bool,
+}
+
+impl SlotReport {
+    fn new(
+        config: &ConfigData,
+        slots: Vec<status::SlotStatus>,
+        temp: Option<f32>,
+        drop_in_progress: bool,
+    ) -> Self {
+        SlotReport {
+            api_version: "2",
+            machine_id: config.machine_id.clone(),
+            total_slots: slots.len(),
+            stocked_slots_count: slots.iter().filter(|slot| slot.stocked).count(),
+            unreadable_slots_count: slots.iter().filter(|slot| slot.error.is_some()).count(),
+            generated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            slots,
+            temp,
+            drop_in_progress,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, ToSchema)]
+struct TemperatureReport {
+    /// `null` without a sensor, or when it keeps failing
+    celsius: Option<f32>,
+    fahrenheit: Option<f32>,
+    /// When the last good reading was taken
+    timestamp: Option<String>,
+    /// `null` when there's no sensor
+    sensor_id: Option<String>,
+    /// Mean of the cached readings in `history`
+    smoothed: Option<f32>,
+    /// Same as `celsius`, as read off the sensor
+    raw: Option<f32>,
+    /// Oldest first
+    history: Vec<TemperatureReading>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+#[validate(context = SlotCount)]
 struct DropRequest {
-    slot: usize,
+    /// 0-based slot index, matching `SlotStatus.number` from `/slots`.
+    /// Slot *numbers* are these indices, slot *IDs* are the hardware
+    /// addresses in `SlotStatus.id`. Also accepted as `slot`, which is
+    /// deprecated.
+    #[serde(alias = "slot")]
+    #[validate(custom(function = "slot_exists", use_context))]
+    slot_number: usize,
+    /// Drop this many from the slot one after another, at most
+    /// `BUB_MAX_COUNT_PER_DROP`. Setting it, even to 1, answers with a
+    /// `DropsResponse` instead of a `DropResponse`.
+    count: Option<u32>,
+    /// Anything the client wants to find again in `/drops`, like a POS
+    /// order ID. Kept as is, up to `BUB_METADATA_MAX_BYTES` of JSON.
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<serde_json::Value>,
+    /// Who's asking, only used in the logs
+    operator_id: Option<String>,
+    /// Don't read the slot's stocked switch first. Empty slots get dropped
+    /// from either way, this only skips the warning about it.
+    #[serde(default)]
+    skip_stocked_check: bool,
+    /// Keep the motor on this long instead of `BUB_DROP_DELAY`, at most
+    /// 60000
+    #[validate(range(max = MAX_DROP_DELAY_MS))]
+    custom_delay_ms: Option<u64>,
+}
+
+impl DropRequest {
+    /// The per-drop options this request asks for
+    fn options(&self, request_id: Option<String>) -> DropOptions {
+        DropOptions {
+            operator_id: self.operator_id.clone(),
+            request_id,
+            skip_stocked_check: self.skip_stocked_check,
+            custom_delay_ms: self.custom_delay_ms,
+            ..DropOptions::default()
+        }
+    }
+}
+
+/// How many slots the machine has, to check a `DropRequest` against
+struct SlotCount(usize);
+
+fn slot_exists(slot_number: usize, slot_count: &SlotCount) -> Result<(), ValidationError> {
+    if slot_number < slot_count.0 {
+        return Ok(());
+    }
+    Err(ValidationError::new("no_such_slot"))
+}
+
+#[derive(Serialize, ToSchema)]
+struct DropResult {
+    /// 1-based
+    attempt: u32,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drop: Option<DropResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<DropErrorRes>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+struct DropsResponse {
+    /// Stops after the first failed attempt
+    results: Vec<DropResult>,
+}
+
+#[derive(Serialize, ToSchema)]
 struct DropResponse {
     message: String,
+    /// The slot that was dropped from
+    slot: usize,
+    /// Its hardware address, the same as `SlotStatus.id`
+    slot_id: String,
+    slot_name: Option<String>,
+    drop_method: DropMethod,
+    duration_ms: u64,
+    /// How long the motor was on for
+    motor_ms: u64,
+    /// How long the cam took to come back around, for slots with one
+    cam_ms: Option<u64>,
+    latch_opened: bool,
+    timestamp: String,
+    /// `timestamp`, for the `Last-Modified` header
+    #[serde(skip)]
+    dropped_at: DateTime<Utc>,
 }
 
-#[derive(Serialize)]
+#[derive(ToSchema)]
 #[allow(non_snake_case)]
 struct DropErrorRes {
     error: String,
     errorCode: u16,
+    /// When it's worth trying again, as an RFC 3339 timestamp. Only sent
+    /// with 503s and 429s, as `null` when we can't tell.
+    #[schema(value_type = Option<String>)]
+    retry_after: Option<DateTime<Utc>>,
+    /// Numbers that explain the error, like `waited_ms`. Sent as fields of
+    /// their own rather than nested.
+    #[schema(value_type = Object)]
+    details: Vec<(&'static str, u64)>,
+}
+
+impl Serialize for DropErrorRes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let retryable = matches!(self.errorCode, 429 | 503);
+        let mut state = serializer
+            .serialize_struct("DropErrorRes", 2 + retryable as usize + self.details.len())?;
+        state.serialize_field("error", &self.error)?;
+        state.serialize_field("errorCode", &self.errorCode)?;
+        for (name, value) in &self.details {
+            state.serialize_field(name, value)?;
+        }
+        if retryable {
+            state.serialize_field(
+                "retry_after",
+                &self
+                    .retry_after
+                    .map(|at| at.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            )?;
+        }
+        state.end()
+    }
+}
+
+/// How a `DropError` goes out, so it looks like every other error response
+impl From<DropError> for DropErrorRes {
+    fn from(err: DropError) -> Self {
+        DropErrorRes {
+            error: err.to_string(),
+            errorCode: err.status_code(),
+            retry_after: None,
+            details: err.details(),
+        }
+    }
+}
+
+impl DropErrorRes {
+    /// Sends this with `errorCode` as the HTTP status, and `retry_after` as
+    /// `Retry-After` when we know it
+    fn respond(self) -> HttpResponse {
+        let mut res = HttpResponse::build(StatusCode::from_u16(self.errorCode).unwrap());
+        if let Some(retry_after) = self.retry_after {
+            res.insert_header((RETRY_AFTER, HttpDate::from(SystemTime::from(retry_after))));
+        }
+        res.json(self)
+    }
+
+    /// For a slot number past the last slot, wherever it turns up
+    fn no_such_slot(slot: usize, slot_count: usize) -> Self {
+        DropErrorRes {
+            error: format!(
+                "Slot {} doesn't exist, this machine has {} slots (numbered from 0)",
+                slot, slot_count
+            ),
+            errorCode: 422,
+            retry_after: None,
+            details: Vec::new(),
+        }
+    }
+
+    /// For anything that needs the slots while a reload has them
+    fn reloading() -> Self {
+        DropErrorRes {
+            error: "Reloading the config, try again shortly".to_string(),
+            errorCode: 503,
+            retry_after: Some(Utc::now() + RELOAD_RELEASE_TIMEOUT),
+            details: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct RebootResponse {
+    message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SchedulerDiagnostics {
+    policy: Option<i32>,
+    priority: Option<i32>,
+    realtime_priority: i32,
+    /// Whether drops can get real-time priority under `BUB_RT_POLICY`, as
+    /// found when the server started. `None` if we couldn't tell.
+    realtime_capable: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Diagnostics {
+    scheduler: SchedulerDiagnostics,
+    latch_healthy: Option<bool>,
+    /// OS threads in the process, see `/debug/threads` for the list
+    threads: Option<usize>,
+    /// Tasks on the runtime of the actix worker that answered
+    tokio_tasks: usize,
+    /// Only slots with a motor fault pin
+    motor_fault_pins: Vec<MotorFaultPin>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct MotorFaultPin {
+    slot: usize,
+    pin: u32,
+    /// `null` if the pin couldn't be read, e.g. mid-drop
+    raised: Option<bool>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct StockOverrideRequest {
+    /// `null` goes back to reading the hardware
+    stocked: Option<bool>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct VerifyRequest {
+    /// How long to watch for, at most 60000. Defaults to 5000.
+    #[serde(default = "default_verify_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_verify_timeout_ms() -> u64 {
+    5000
+}
+
+const MAX_VERIFY_TIMEOUT_MS: u64 = 60_000;
+/// How often to read the stocked switch while verifying
+const VERIFY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum VerifyFailure {
+    NoStockChange,
+}
+
+#[derive(Serialize, ToSchema)]
+struct VerifyResponse {
+    verified: bool,
+    /// How long it took to go empty, when it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<VerifyFailure>,
+}
+
+/// Fields that are left out stay as they were
+#[derive(Deserialize, ToSchema)]
+struct SlotMetaRequest {
+    /// At most 64 characters
+    name: Option<String>,
+    /// At most 256 characters
+    description: Option<String>,
+    /// 0 to 9999
+    price_cents: Option<u32>,
+}
+
+const MAX_SLOT_NAME_LEN: usize = 64;
+const MAX_SLOT_DESCRIPTION_LEN: usize = 256;
+const MAX_SLOT_PRICE_CENTS: u32 = 9999;
+
+impl SlotMetaRequest {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.name {
+            if name.chars().count() > MAX_SLOT_NAME_LEN {
+                return Err(format!(
+                    "Name can't be longer than {} characters",
+                    MAX_SLOT_NAME_LEN
+                ));
+            }
+        }
+        if let Some(description) = &self.description {
+            if description.chars().count() > MAX_SLOT_DESCRIPTION_LEN {
+                return Err(format!(
+                    "Description can't be longer than {} characters",
+                    MAX_SLOT_DESCRIPTION_LEN
+                ));
+            }
+        }
+        if let Some(price_cents) = self.price_cents {
+            if price_cents > MAX_SLOT_PRICE_CENTS {
+                return Err(format!(
+                    "Price can't be more than {} cents",
+                    MAX_SLOT_PRICE_CENTS
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct MachineStatus {
+    offline: bool,
+}
+
+#[derive(Deserialize)]
+struct ClearDropsQuery {
+    /// Only clear drops from before this, as an RFC 3339 timestamp
+    before: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ClearDropsResponse {
+    /// How many drops were removed
+    cleared: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReloadResponse {
+    /// How many slots the new config has
+    total_slots: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ConfigSummary {
+    slots: Vec<String>,
+    temperature_id: Option<String>,
+    drop_delay_ms: u64,
+    allow_remote_reboot: bool,
+    latch: bool,
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+struct MachineStateQuery {
+    /// Comma separated sections to include, defaults to all of them
+    fields: Option<String>,
+}
+
+/// Everything a dashboard wants in one request. Sections that weren't asked
+/// for are left out entirely.
+#[derive(Serialize, ToSchema)]
+struct MachineState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<HealthReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<ConfigSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<DropStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature_history: Option<Vec<TemperatureReading>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_drops: Option<Vec<DropRecord>>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        drop,
+        health,
+        get_slots,
+        get_slot,
+        get_temperature,
+        get_drops,
+        clear_drops,
+        get_stock_events,
+        stock_override,
+        verify_slot,
+        update_slot_meta,
+        cam_history,
+        slot_stocked,
+        set_offline,
+        set_online,
+        emergency_stop,
+        reload_config,
+        machine_state,
+        reboot,
+        diagnostics
+    ),
+    components(schemas(
+        HealthReport,
+        SlotReport,
+        TemperatureReport,
+        DropRequest,
+        DropResponse,
+        DropResult,
+        DropsResponse,
+        DropErrorRes,
+        RebootResponse,
+        SchedulerDiagnostics,
+        Diagnostics,
+        MotorFaultPin,
+        MachineStatus,
+        ClearDropsResponse,
+        ReloadResponse,
+        MachineState,
+        ConfigSummary,
+        DropStats,
+        TemperatureReading,
+        DropRecord,
+        StockEvent,
+        status::LegacySlotEntry,
+        status::SlotStatus,
+        status::StockedSource,
+        StockOverrideRequest,
+        VerifyRequest,
+        VerifyResponse,
+        VerifyFailure,
+        SlotMetaRequest,
+        slot_stats::SlotMeta,
+        slot_stats::StockedCheck,
+        CamPulseRecord,
+        CamPulseResult,
+        DropMethod,
+    )),
+    modifiers(&DeprecateLegacy)
+)]
+struct ApiDoc;
+
+/// `/health` only sticks around for old clients, `/slots` replaces it
+struct DeprecateLegacy;
+
+impl Modify for DeprecateLegacy {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(path) = openapi.paths.paths.get_mut("/health") {
+            for operation in path.operations.values_mut() {
+                operation.deprecated = Some(Deprecated::True);
+            }
+        }
+    }
 }
 
+#[utoipa::path(
+    request_body(
+        content = DropRequest,
+        description = "JSON, the same fields form-encoded (application/x-www-form-urlencoded), or just the slot number as text/plain"
+    ),
+    responses(
+        (status = 200, description = "Drink dropped. A DropsResponse instead when `count` is set and at least one dropped.", body = DropResponse),
+        (status = 400, description = "Bad count, custom_delay_ms over 60000, or a text/plain body that isn't a slot number", body = DropErrorRes),
+        (status = 413, description = "metadata is over BUB_METADATA_MAX_BYTES", body = DropErrorRes),
+        (status = 415, description = "Body isn't JSON, a form or a text/plain slot number", body = DropErrorRes),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+        (status = 500, description = "Motor problem", body = DropErrorRes),
+        (status = 503, description = "Machine offline, reloading its config, or the drop was cancelled, with retry_after", body = DropErrorRes),
+    )
+)]
 #[post("/drop")]
-async fn drop(data: web::Data<AppData>, req_body: web::Json<DropRequest>) -> impl Responder {
+async fn drop(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    // Each extractor only accepts its own Content-Type, so this picks
+    // whichever one the request was sent as. Bytes takes anything, so it
+    // goes last and checks for text/plain itself.
+    req_body: Either<Either<web::Json<DropRequest>, web::Form<DropRequest>>, web::Bytes>,
+) -> impl Responder {
+    let req_body = match req_body {
+        Either::Left(Either::Left(json)) => json.into_inner(),
+        Either::Left(Either::Right(form)) => form.into_inner(),
+        Either::Right(text) => match plain_text_drop(&req, &text) {
+            Ok(req_body) => req_body,
+            Err(res) => return res.respond(),
+        },
+    };
+    let request_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string);
+    let (slot_count, metadata_max_bytes) = {
+        let config = data.config();
+        (config.slots.len(), config.metadata_max_bytes)
+    };
+    // Before the drop lock, so a bad slot never waits behind a drop.
+    // perform_drop checks again for websocket drops, which skip this.
+    if let Err(errors) = req_body.validate_with_args(&SlotCount(slot_count)) {
+        if errors.field_errors().contains_key("slot_number") {
+            return DropErrorRes::no_such_slot(req_body.slot_number, slot_count).respond();
+        }
+        return DropErrorRes {
+            error: format!("custom_delay_ms can be at most {}", MAX_DROP_DELAY_MS),
+            errorCode: 400,
+            retry_after: None,
+            details: Vec::new(),
+        }
+        .respond();
+    }
+    if let Err(res) = check_metadata(req_body.metadata.as_ref(), metadata_max_bytes) {
+        return res.respond();
+    }
+    let options = req_body.options(request_id);
+    if let Some(count) = req_body.count {
+        return match perform_drops(
+            &data,
+            &slot_stats,
+            req_body.slot_number,
+            count,
+            options,
+            req_body.metadata,
+        )
+        .await
+        {
+            Ok(res) => HttpResponse::Ok().json(res),
+            Err(res) => res.respond(),
+        };
+    }
+    match perform_drop(
+        &data,
+        &slot_stats,
+        req_body.slot_number,
+        options,
+        req_body.metadata,
+    )
+    .await
+    {
+        Ok(res) => HttpResponse::Ok()
+            .insert_header(LastModified(HttpDate::from(SystemTime::from(
+                res.dropped_at,
+            ))))
+            .insert_header(ETag(drop_etag(res.slot, res.dropped_at)))
+            .json(res),
+        Err(res) => res.respond(),
+    }
+}
+
+/// Lets the request through if `BUB_API_KEY` isn't set or the request has it
+/// as a bearer token, otherwise gives back the 401 to send
+fn check_api_key(req: &HttpRequest, config: &ConfigData) -> Result<(), DropErrorRes> {
+    let api_key = match config.api_key.as_deref() {
+        Some(api_key) => api_key,
+        None => return Ok(()),
+    };
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        == Some(api_key);
+    if !authorized {
+        return Err(DropErrorRes {
+            error: "Missing or invalid API key".to_string(),
+            errorCode: 401,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    Ok(())
+}
+
+/// Like `check_api_key`, but refuses everyone when there's no key set
+fn require_api_key(req: &HttpRequest, config: &ConfigData) -> Result<(), DropErrorRes> {
+    if config.api_key.is_none() {
+        return Err(DropErrorRes {
+            error: "Set BUB_API_KEY to use admin endpoints".to_string(),
+            errorCode: 403,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    check_api_key(req, config)
+}
+
+/// A `text/plain` body with nothing but the slot number in it, for curl
+/// one-liners and buttons that can't build JSON
+fn plain_text_drop(req: &HttpRequest, body: &[u8]) -> Result<DropRequest, DropErrorRes> {
+    if req.content_type() != "text/plain" {
+        return Err(DropErrorRes {
+            error: "Send JSON, a form, or a text/plain slot number".to_string(),
+            errorCode: 415,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    let slot_number = std::str::from_utf8(body)
+        .ok()
+        .and_then(|body| body.trim().parse::<usize>().ok())
+        .ok_or_else(|| DropErrorRes {
+            error: "A text/plain body has to be just the slot number".to_string(),
+            errorCode: 400,
+            retry_after: None,
+            details: Vec::new(),
+        })?;
+    Ok(DropRequest {
+        slot_number,
+        count: None,
+        metadata: None,
+        operator_id: None,
+        skip_stocked_check: false,
+        custom_delay_ms: None,
+    })
+}
+
+/// Refuses metadata that comes to more than `max_bytes` as JSON
+fn check_metadata(
+    metadata: Option<&serde_json::Value>,
+    max_bytes: usize,
+) -> Result<(), DropErrorRes> {
+    let size = metadata.map_or(0, |metadata| metadata.to_string().len());
+    if size > max_bytes {
+        return Err(DropErrorRes {
+            error: format!(
+                "metadata is {} bytes, but can be at most {}",
+                size, max_bytes
+            ),
+            errorCode: 413,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    Ok(())
+}
+
+/// Unique to each drop, since no two drops start at the same millisecond
+fn drop_etag(slot: usize, dropped_at: DateTime<Utc>) -> EntityTag {
+    EntityTag::new_strong(format!("{}-{}", dropped_at.timestamp_millis(), slot))
+}
+
+/// `count` drops from one slot, `BUB_MULTI_DROP_INTERVAL_MS` apart. Gives up
+/// after the first one that fails, and if that's the first one, answers
+/// with its error like a single drop would.
+async fn perform_drops(
+    data: &AppData,
+    slot_stats: &SlotStatsRegistry,
+    slot: usize,
+    count: u32,
+    options: DropOptions,
+    metadata: Option<serde_json::Value>,
+) -> Result<DropsResponse, DropErrorRes> {
+    let (max_count, interval) = {
+        let config = data.config();
+        (config.max_count_per_drop, config.multi_drop_interval_ms)
+    };
+    if count == 0 || count > max_count {
+        return Err(DropErrorRes {
+            error: format!("count has to be between 1 and {}", max_count),
+            errorCode: 400,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    let mut results = Vec::new();
+    for attempt in 1..=count {
+        if attempt > 1 {
+            tokio::time::sleep(Duration::from_millis(interval)).await;
+        }
+        match perform_drop(data, slot_stats, slot, options.clone(), metadata.clone()).await {
+            Ok(res) => results.push(DropResult {
+                attempt,
+                success: true,
+                drop: Some(res),
+                error: None,
+            }),
+            Err(res) if attempt == 1 => return Err(res),
+            Err(res) => {
+                results.push(DropResult {
+                    attempt,
+                    success: false,
+                    drop: None,
+                    error: Some(res),
+                });
+                break;
+            }
+        }
+    }
+    Ok(DropsResponse { results })
+}
+
+/// Holds a flag like `drop_in_progress` set until it goes out of scope, so
+/// a drop that panics partway doesn't leave it set forever
+struct InProgress<'a>(&'a AtomicBool);
+
+impl<'a> InProgress<'a> {
+    fn start(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Ordering::SeqCst);
+        InProgress(flag)
+    }
+}
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Everything `/drop` does, minus the HTTP. Also used for drops requested
+/// over the websocket. `options` carries what the request asked for, the
+/// rest of it is filled in here.
+async fn perform_drop(
+    data: &AppData,
+    slot_stats: &SlotStatsRegistry,
+    slot: usize,
+    options: DropOptions,
+    metadata: Option<serde_json::Value>,
+) -> Result<DropResponse, DropErrorRes> {
+    if data.reloading.load(Ordering::SeqCst) {
+        return Err(DropErrorRes::reloading());
+    }
+    if data.offline.load(Ordering::SeqCst) {
+        return Err(DropErrorRes {
+            error: "Machine temporarily offline".to_string(),
+            errorCode: 503,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    // Catch nonsense slots before announcing a drop or recording it
+    let slot_count = data.slot_count();
+    if slot >= slot_count {
+        return Err(DropErrorRes::no_such_slot(slot, slot_count));
+    }
+    let timestamp = Utc::now();
+    let started = Instant::now();
+    let (stocked_override, slot_name) = slot_stats
+        .lock()
+        .await
+        .get(&slot)
+        .map(|state| (state.stocked_override, state.meta.name.clone()))
+        .unwrap_or_default();
+    data.emit(MachineEvent::DropStarted { slot });
     let drop_result = {
-        let config = data.config.lock().await;
-        machine::drop(config.deref(), req_body.slot).await
+        // One motor at a time
+        let _drop_lock = data.drop_lock.lock().await;
+        let config = data.config();
+        let options = DropOptions {
+            dry_run: data.dry_run,
+            stocked_override,
+            cancel: Some(data.drop_cancel()),
+            ..options
+        };
+        let in_progress = InProgress::start(&data.drop_in_progress);
+        let outcome = machine::drop::drop(config.deref(), slot, options).await;
+        std::mem::drop(in_progress);
+        if let Some(pulse) = outcome.cam_pulse {
+            let mut states = slot_stats.lock().await;
+            push_bounded(
+                &mut states.entry(slot).or_default().cam_history,
+                CamPulseRecord {
+                    drop_at: timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    pulse_ms: pulse.duration.as_millis() as u64,
+                    result: if pulse.timed_out {
+                        CamPulseResult::Timeout
+                    } else {
+                        CamPulseResult::Success
+                    },
+                },
+                config.cam_history_size,
+            );
+        }
+        if outcome.result.is_ok() {
+            slot_stats.lock().await.entry(slot).or_default().drop_count += 1;
+        }
+        outcome.result
+    };
+    let record = DropRecord {
+        slot,
+        success: drop_result.is_ok(),
+        error: drop_result.as_ref().err().map(ToString::to_string),
+        duration_ms: started.elapsed().as_millis() as u64,
+        timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        metadata,
     };
+    data.history
+        .lock()
+        .await
+        .record_drop(record.clone(), timestamp);
+    data.emit(MachineEvent::DropCompleted(record));
     match drop_result {
-        Ok(_) => HttpResponse::Ok().json(DropResponse {
-            message: "Dropped drink from slot ".to_string() + &req_body.slot.to_string(),
+        Ok(dropped) => Ok(DropResponse {
+            message: "Dropped drink from slot_number ".to_string() + &slot.to_string(),
+            slot: dropped.slot,
+            slot_id: dropped.slot_id.clone(),
+            slot_name,
+            drop_method: dropped.method,
+            duration_ms: started.elapsed().as_millis() as u64,
+            motor_ms: dropped.motor_duration().as_millis() as u64,
+            cam_ms: dropped
+                .cam_duration()
+                .map(|duration| duration.as_millis() as u64),
+            latch_opened: dropped.latch_opened,
+            timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            dropped_at: timestamp,
         }),
-        Err(DropError::BadSlot) => {
-            HttpResponse::Ok()
-                .status(StatusCode::BAD_REQUEST)
-                .json(DropErrorRes {
-                    error: "Invalid slot ID provided".to_string(),
-                    errorCode: 400,
-                })
-        }
-        Err(err) => HttpResponse::Ok()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .json(DropErrorRes {
-                error: err.to_string(),
-                errorCode: 500,
-            }),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[utoipa::path(
+    responses((status = 200, description = "Recent drops, oldest first", body = [DropRecord]))
+)]
+#[get("/drops")]
+async fn get_drops(data: web::Data<AppData>) -> impl Responder {
+    let history = data.history.lock().await;
+    let mut res = HttpResponse::Ok();
+    if let Some(last_drop_at) = history.last_drop_at {
+        // The count changes when the history gets cleared, even though
+        // there hasn't been a new drop
+        res.insert_header(LastModified(HttpDate::from(SystemTime::from(last_drop_at))))
+            .insert_header(ETag(EntityTag::new_strong(format!(
+                "{}-{}-{}",
+                last_drop_at.timestamp_millis(),
+                history.stats.total_drops,
+                history.drop_count()
+            ))));
+    }
+    res.json(history.drops())
+}
+
+#[utoipa::path(
+    params(("before" = Option<String>, Query, description = "Only clear drops from before this RFC 3339 timestamp")),
+    responses(
+        (status = 200, description = "How many drops were cleared", body = ClearDropsResponse),
+        (status = 401, description = "Missing or wrong API key", body = DropErrorRes),
+        (status = 403, description = "BUB_API_KEY isn't set", body = DropErrorRes),
+    )
+)]
+#[delete("/drops/history")]
+async fn clear_drops(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    query: web::Query<ClearDropsQuery>,
+) -> impl Responder {
+    if let Err(res) = require_api_key(&req, &data.config()) {
+        return res.respond();
     }
+    let cleared = data.history.lock().await.clear_drops(query.before);
+    info!("Cleared {} drops from the history", cleared);
+    HttpResponse::Ok().json(ClearDropsResponse { cleared })
 }
 
+#[utoipa::path(
+    responses((status = 200, description = "Recent stock changes, oldest first", body = [StockEvent]))
+)]
+#[get("/stock-events")]
+async fn get_stock_events(data: web::Data<AppData>) -> impl Responder {
+    HttpResponse::Ok().json(data.stock_events.lock().await.events())
+}
+
+#[utoipa::path(
+    responses((status = 200, description = "Legacy machine health", body = HealthReport))
+)]
 #[get("/health")]
-async fn health(data: web::Data<AppData>) -> impl Responder {
-    let config = data.config.lock().await;
-    let slots = machine::get_slots_old(config.deref());
-    let temperature = machine::get_temperature(config.deref());
+async fn health(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+) -> impl Responder {
+    let drop_in_progress = data.drop_in_progress.load(Ordering::SeqCst);
+    let config = data.config();
+    let slots = status::get_slots_old(config.deref(), &*slot_stats.lock().await);
+    let temperature = read_temperature(&data, config.deref()).await;
 
-    let temperature = temperature * (9.0 / 5.0) + 32.0;
+    let temperature = temperature.map(|temperature| temperature * (9.0 / 5.0) + 32.0);
 
     HttpResponse::Ok().json(HealthReport {
-        slots: slots.to_vec(),
+        slots,
         temp: temperature,
+        offline: data.offline.load(Ordering::SeqCst),
+        drop_in_progress,
     })
 }
 
+/// Quotes `field` if it would otherwise break the row
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// One row per slot, for scripts that would rather not parse JSON
+fn slots_csv(slots: &[status::SlotStatus], states: &HashMap<usize, SlotState>) -> String {
+    let mut csv = "number,id,name,stocked,read_error,drop_count\n".to_string();
+    for slot in slots {
+        let drop_count = states
+            .get(&(slot.number as usize))
+            .map(|state| state.drop_count)
+            .unwrap_or_default();
+        csv += &format!(
+            "{},{},{},{},{},{}\n",
+            slot.number,
+            csv_field(&slot.id),
+            csv_field(slot.meta.name.as_deref().unwrap_or_default()),
+            slot.stocked,
+            slot.error.is_some(),
+            drop_count
+        );
+    }
+    csv
+}
+
+#[utoipa::path(
+    params(("Accept" = Option<String>, Header, description = "text/csv for a CSV table instead of JSON")),
+    responses(
+        (status = 200, description = "Status of every slot", body = SlotReport),
+        (status = 503, description = "A config reload is in progress, with retry_after", body = DropErrorRes),
+    )
+)]
 #[get("/slots")]
-async fn get_slots(data: web::Data<AppData>) -> impl Responder {
-    let config = data.config.lock().await;
-    let slots = machine::get_slots(config.deref());
-    let temp = machine::get_temperature(config.deref());
+async fn get_slots(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+) -> impl Responder {
+    if data.reloading.load(Ordering::SeqCst) {
+        return DropErrorRes::reloading().respond();
+    }
+    let drop_in_progress = data.drop_in_progress.load(Ordering::SeqCst);
+    let config = data.config();
+    let states = slot_stats.lock().await;
+    let slots = status::get_slots(config.deref(), &states);
+    let wants_csv = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| {
+            accept
+                .split(',')
+                .any(|mime| mime.split(';').next().unwrap_or_default().trim() == "text/csv")
+        });
+    if wants_csv {
+        return HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(slots_csv(&slots, &states));
+    }
+    std::mem::drop(states);
+    let temp = read_temperature(&data, config.deref()).await;
+
+    HttpResponse::Ok().json(SlotReport::new(
+        config.deref(),
+        slots,
+        temp,
+        drop_in_progress,
+    ))
+}
+
+#[utoipa::path(
+    params(
+        ("id" = usize, Path, description = "0-based slot index"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag from a previous response"),
+    ),
+    responses(
+        (status = 200, description = "Status of one slot", body = SlotStatus),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+        (status = 503, description = "A config reload is in progress, with retry_after", body = DropErrorRes),
+    )
+)]
+#[get("/slots/{id}")]
+async fn get_slot(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    path: web::Path<usize>,
+) -> impl Responder {
+    if data.reloading.load(Ordering::SeqCst) {
+        return DropErrorRes::reloading().respond();
+    }
+    let slot = path.into_inner();
+    let config = data.config();
+    let mut states = slot_stats.lock().await;
+    let status = match status::get_slot(config.deref(), &states, slot) {
+        Some(status) => status,
+        None => return DropErrorRes::no_such_slot(slot, config.slots.len()).respond(),
+    };
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&status).unwrap().hash(&mut hasher);
+    let etag = EntityTag::new_strong(format!("{:016x}", hasher.finish()));
+    states.entry(slot).or_default().last_etag = Some(etag.tag().to_string());
+
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if unchanged {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish();
+    }
+    HttpResponse::Ok().insert_header(ETag(etag)).json(status)
+}
 
-    HttpResponse::Ok().json(SlotReport { slots, temp })
+#[utoipa::path(
+    params(("id" = usize, Path, description = "0-based slot index")),
+    responses(
+        (status = 200, description = "What the slot's stocked switch reads right now", body = StockedCheck),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+        (status = 500, description = "Couldn't read the switch", body = DropErrorRes),
+        (status = 503, description = "A config reload is in progress, with retry_after", body = DropErrorRes),
+    )
+)]
+#[get("/slots/{id}/stocked")]
+async fn slot_stocked(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    path: web::Path<usize>,
+) -> impl Responder {
+    let slot = path.into_inner();
+    if data.reloading.load(Ordering::SeqCst) {
+        return DropErrorRes::reloading().respond();
+    }
+    let (slot_config, inverted) = {
+        let config = data.config();
+        match config.slots.get(slot) {
+            Some(slot_config) => (slot_config.clone(), config.stocked_inverted(slot)),
+            None => return DropErrorRes::no_such_slot(slot, config.slots.len()).respond(),
+        }
+    };
+    // Straight from the switch, like verify_slot, so overrides don't count
+    let stocked = match status::is_stocked(&slot_config, inverted) {
+        Ok(stocked) => stocked,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(DropErrorRes {
+                error: format!("Couldn't read slot {}'s stocked switch: {}", slot, err),
+                errorCode: 500,
+                retry_after: None,
+                details: Vec::new(),
+            })
+        }
+    };
+    let check = StockedCheck {
+        slot,
+        stocked,
+        checked_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    };
+    slot_stats
+        .lock()
+        .await
+        .entry(slot)
+        .or_default()
+        .last_stocked_check = Some(check.clone());
+    HttpResponse::Ok().json(check)
 }
+
+#[utoipa::path(
+    responses((status = 200, description = "Current temperature and recent readings", body = TemperatureReport))
+)]
+#[get("/temperature")]
+async fn get_temperature(data: web::Data<AppData>) -> impl Responder {
+    let config = data.config();
+    let celsius = read_temperature(&data, config.deref()).await;
+    let history = data.history.lock().await.temperatures();
+    let smoothed = (!history.is_empty())
+        .then(|| history.iter().map(|reading| reading.temp).sum::<f32>() / history.len() as f32);
+
+    HttpResponse::Ok().json(TemperatureReport {
+        celsius,
+        fahrenheit: celsius.map(|celsius| celsius * (9.0 / 5.0) + 32.0),
+        timestamp: history.last().map(|reading| reading.timestamp.clone()),
+        sensor_id: config.temperature_id.clone(),
+        smoothed,
+        raw: celsius,
+        history,
+    })
+}
+
+/// Reads the temperature and keeps it for `/machine/state`. A failed read
+/// gives the last good reading until `temp_error_threshold` of them happen
+/// in a row, then `None` until the sensor comes back.
+async fn read_temperature(data: &AppData, config: &ConfigData) -> Option<f32> {
+    // Nothing to read, and nothing failing either
+    config.temperature_id.as_ref()?;
+    let mut history = data.history.lock().await;
+    let temp = match temperature::get_temperature(config) {
+        Some(temp) => {
+            data.temp_failures.store(0, Ordering::SeqCst);
+            history.record_temperature(TemperatureReading {
+                temp,
+                timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            });
+            Some(temp)
+        }
+        None => {
+            let failures = data.temp_failures.fetch_add(1, Ordering::SeqCst) + 1;
+            if failures >= config.temp_error_threshold {
+                None
+            } else {
+                history.last_temperature().map(|reading| reading.temp)
+            }
+        }
+    };
+    data.emit(MachineEvent::TemperatureUpdated { temp });
+    temp
+}
+
+#[utoipa::path(
+    request_body = StockOverrideRequest,
+    params(("id" = usize, Path, description = "0-based slot index")),
+    responses(
+        (status = 200, description = "Slot with the override applied", body = SlotStatus),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+    )
+)]
+#[post("/slots/{id}/stock-override")]
+async fn stock_override(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    path: web::Path<usize>,
+    req_body: web::Json<StockOverrideRequest>,
+) -> impl Responder {
+    let slot = path.into_inner();
+    let config = data.config();
+    if slot >= config.slots.len() {
+        return DropErrorRes::no_such_slot(slot, config.slots.len()).respond();
+    }
+    let mut states = slot_stats.lock().await;
+    match req_body.stocked {
+        Some(stocked) => info!("Overriding slot {} to stocked={}", slot, stocked),
+        None => info!("Clearing stocked override for slot {}", slot),
+    }
+    states.entry(slot).or_default().stocked_override = req_body.stocked;
+
+    let status = status::get_slot(config.deref(), &states, slot);
+    if let Some(status) = status.clone() {
+        data.emit(MachineEvent::SlotChanged(status));
+    }
+    HttpResponse::Ok().json(status)
+}
+
+#[utoipa::path(
+    request_body = VerifyRequest,
+    params(("id" = usize, Path, description = "0-based slot index")),
+    responses(
+        (status = 200, description = "Whether the slot went from stocked to empty in time", body = VerifyResponse),
+        (status = 422, description = "No such slot, or timeout_ms is too long", body = DropErrorRes),
+        (status = 503, description = "A config reload is in progress, with retry_after", body = DropErrorRes),
+    )
+)]
+#[post("/slots/{id}/verify")]
+async fn verify_slot(
+    data: web::Data<AppData>,
+    path: web::Path<usize>,
+    req_body: web::Json<VerifyRequest>,
+) -> impl Responder {
+    let slot = path.into_inner();
+    if req_body.timeout_ms > MAX_VERIFY_TIMEOUT_MS {
+        return HttpResponse::UnprocessableEntity().json(DropErrorRes {
+            error: format!("timeout_ms can be at most {}", MAX_VERIFY_TIMEOUT_MS),
+            errorCode: 422,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    if data.reloading.load(Ordering::SeqCst) {
+        return DropErrorRes::reloading().respond();
+    }
+    let (slot_config, inverted) = {
+        let config = data.config();
+        match config.slots.get(slot) {
+            Some(slot_config) => (slot_config.clone(), config.stocked_inverted(slot)),
+            None => return DropErrorRes::no_such_slot(slot, config.slots.len()).respond(),
+        }
+    };
+
+    // This goes straight to the switch, stock overrides don't count
+    let started = Instant::now();
+    let timeout = Duration::from_millis(req_body.timeout_ms);
+    let mut was_stocked = false;
+    let mut complained = false;
+    loop {
+        match status::is_stocked(&slot_config, inverted) {
+            Ok(true) => was_stocked = true,
+            Ok(false) if was_stocked => {
+                return HttpResponse::Ok().json(VerifyResponse {
+                    verified: true,
+                    elapsed_ms: Some(started.elapsed().as_millis() as u64),
+                    reason: None,
+                })
+            }
+            Ok(false) => {}
+            // Once is plenty at this rate
+            Err(err) if !complained => {
+                warn!("Couldn't read slot {} while verifying: {:?}", slot, err);
+                complained = true;
+            }
+            Err(_) => {}
+        }
+        if started.elapsed() >= timeout {
+            return HttpResponse::Ok().json(VerifyResponse {
+                verified: false,
+                elapsed_ms: None,
+                reason: Some(VerifyFailure::NoStockChange),
+            });
+        }
+        tokio::time::sleep(VERIFY_POLL_INTERVAL).await;
+    }
+}
+
+#[utoipa::path(
+    request_body = SlotMetaRequest,
+    params(("id" = usize, Path, description = "0-based slot index")),
+    responses(
+        (status = 200, description = "Slot with the new metadata", body = SlotStatus),
+        (status = 400, description = "Invalid metadata", body = DropErrorRes),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+    )
+)]
+#[patch("/slots/{id}/meta")]
+async fn update_slot_meta(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    path: web::Path<usize>,
+    req_body: web::Json<SlotMetaRequest>,
+) -> impl Responder {
+    let slot = path.into_inner();
+    let config = data.config();
+    if slot >= config.slots.len() {
+        return DropErrorRes::no_such_slot(slot, config.slots.len()).respond();
+    }
+    if let Err(error) = req_body.validate() {
+        return HttpResponse::BadRequest().json(DropErrorRes {
+            error,
+            errorCode: 400,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    let req_body = req_body.into_inner();
+    let mut states = slot_stats.lock().await;
+    let meta = &mut states.entry(slot).or_default().meta;
+    if req_body.name.is_some() {
+        meta.name = req_body.name;
+    }
+    if req_body.description.is_some() {
+        meta.description = req_body.description;
+    }
+    if req_body.price_cents.is_some() {
+        meta.price_cents = req_body.price_cents;
+    }
+    info!(
+        "Updated slot {} metadata: name={:?} price_cents={:?}",
+        slot, meta.name, meta.price_cents
+    );
+
+    let status = status::get_slot(config.deref(), &states, slot);
+    if let Some(status) = status.clone() {
+        data.emit(MachineEvent::SlotChanged(status));
+    }
+    HttpResponse::Ok().json(status)
+}
+
+#[utoipa::path(
+    params(("id" = usize, Path, description = "0-based slot index")),
+    responses(
+        (status = 200, description = "Recent cam pulses, oldest first", body = [CamPulseRecord]),
+        (status = 422, description = "No such slot", body = DropErrorRes),
+    )
+)]
+#[get("/slots/{id}/cam-history")]
+async fn cam_history(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    path: web::Path<usize>,
+) -> impl Responder {
+    let slot = path.into_inner();
+    if !data.has_slot(slot) {
+        return DropErrorRes::no_such_slot(slot, data.slot_count()).respond();
+    }
+    let states = slot_stats.lock().await;
+    let history: Vec<&CamPulseRecord> = states
+        .get(&slot)
+        .map(|state| state.cam_history.iter().collect())
+        .unwrap_or_default();
+    HttpResponse::Ok().json(history)
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Machine is offline", body = MachineStatus),
+        (status = 401, description = "Missing or invalid API key", body = DropErrorRes),
+        (status = 403, description = "BUB_API_KEY isn't set", body = DropErrorRes),
+    )
+)]
+#[put("/machine/offline")]
+async fn set_offline(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    if let Err(res) = require_api_key(&req, &data.config()) {
+        return res.respond();
+    }
+    info!("Machine taken offline, refusing drops");
+    data.offline.store(true, Ordering::SeqCst);
+    HttpResponse::Ok().json(MachineStatus { offline: true })
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Machine is online", body = MachineStatus),
+        (status = 401, description = "Missing or invalid API key", body = DropErrorRes),
+        (status = 403, description = "BUB_API_KEY isn't set", body = DropErrorRes),
+    )
+)]
+#[put("/machine/online")]
+async fn set_online(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    if let Err(res) = require_api_key(&req, &data.config()) {
+        return res.respond();
+    }
+    info!("Machine back online, accepting drops");
+    data.reset_drop_cancel();
+    data.offline.store(false, Ordering::SeqCst);
+    HttpResponse::Ok().json(MachineStatus { offline: false })
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Drops cancelled, motors off and machine offline", body = MachineStatus),
+        (status = 401, description = "Missing or invalid API key", body = DropErrorRes),
+        (status = 403, description = "BUB_API_KEY isn't set", body = DropErrorRes),
+    )
+)]
+#[put("/machine/emergency-stop")]
+async fn emergency_stop(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    if let Err(res) = require_api_key(&req, &data.config()) {
+        return res.respond();
+    }
+    warn!("Emergency stop! Cancelling drops and taking the machine offline");
+    data.offline.store(true, Ordering::SeqCst);
+    // Cuts short any drop waiting on its cam, so we get the drop lock
+    // without waiting out the whole turn
+    data.drop_cancel().cancel();
+    let _drop_lock = data.drop_lock.lock().await;
+    let config = data.config();
+    if let Err(err) = machine::drop::stop_all_motors(config.deref()) {
+        error!("Some motors didn't stop: {}", err);
+    }
+    HttpResponse::Ok().json(MachineStatus { offline: true })
+}
+
+/// How long a reload waits for requests to let go of the old config
+const RELOAD_RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why a reload didn't take
+struct ReloadFailure {
+    error: String,
+    /// Whether the old config is back in, rather than the machine being
+    /// left without any slots
+    restored: bool,
+}
+
+/// Puts in the config for `settings`, keeping `old` in until the new one is
+/// known to build. Slots whose hardware hasn't changed carry over, lines and
+/// all. Only when the new one needs lines `old` has for something else is
+/// `old` let go of first, and then `previous` goes back in if the new one
+/// still won't build. Blocks on requesting lines and on the old latch
+/// shutting.
+fn swap_config(
+    data: &AppData,
+    old: Arc<ConfigData>,
+    settings: &Settings,
+    previous: &Settings,
+) -> Result<usize, ReloadFailure> {
+    let err = match ConfigData::reload(settings, &old) {
+        Ok(config) => {
+            let total_slots = config.slots.len();
+            data.config.store(Arc::new(config));
+            return Ok(total_slots);
+        }
+        Err(err) => err,
+    };
+    // Anything else is wrong with the new config itself
+    #[cfg(feature = "gpio")]
+    let busy = matches!(err, crate::config::ConfigError::Gpio { .. });
+    #[cfg(not(feature = "gpio"))]
+    let busy = false;
+    if !busy {
+        return Err(ReloadFailure {
+            error: err.to_string(),
+            restored: true,
+        });
+    }
+
+    info!("Letting go of the old config's lines first: {}", err);
+    data.config.store(Arc::new(old.unwired()));
+    // Anything that loaded it just before the store is only reading
+    let deadline = Instant::now() + RELOAD_RELEASE_TIMEOUT;
+    while Arc::strong_count(&old) > 1 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // Dropping the latch waits for it to shut
+    std::mem::drop(old);
+    let err = match ConfigData::new(settings) {
+        Ok(config) => {
+            let total_slots = config.slots.len();
+            data.config.store(Arc::new(config));
+            return Ok(total_slots);
+        }
+        Err(err) => err,
+    };
+    let restored = match ConfigData::new(previous) {
+        Ok(config) => {
+            data.config.store(Arc::new(config));
+            true
+        }
+        Err(restore_err) => {
+            error!("Couldn't restore the old config either: {}", restore_err);
+            false
+        }
+    };
+    Err(ReloadFailure {
+        error: err.to_string(),
+        restored,
+    })
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The new config is in use", body = ReloadResponse),
+        (status = 400, description = "The config file couldn't be read, nothing changed", body = DropErrorRes),
+        (status = 401, description = "Missing or invalid API key", body = DropErrorRes),
+        (status = 500, description = "The new config didn't work, so the old one is back", body = DropErrorRes),
+        (status = 503, description = "Slots are still in use, nothing changed", body = DropErrorRes),
+    )
+)]
+#[post("/config/reload")]
+async fn reload_config(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+) -> impl Responder {
+    if let Err(res) = check_api_key(&req, &data.config()) {
+        return res.respond();
+    }
+    let mut settings = data.settings.lock().await;
+    let new_settings = match settings.reload() {
+        Ok(new_settings) => new_settings,
+        Err(err) => {
+            return DropErrorRes {
+                error: format!("Not reloading: {}", err),
+                errorCode: 400,
+                retry_after: None,
+                details: Vec::new(),
+            }
+            .respond()
+        }
+    };
+
+    // Nothing new gets hold of a slot from here, and the drop lock waits out
+    // a drop that's already going
+    data.reloading.store(true, Ordering::SeqCst);
+    let _drop_lock = data.drop_lock.lock().await;
+    let mut monitors = data.monitors.lock().await;
+    if let Some(running) = monitors.take() {
+        running.stop().await;
+    }
+    // A slot held elsewhere, like by a verify waiting on its switch, keeps
+    // its lines, so don't take anything down until they've all let go
+    let old = data.config();
+    let deadline = Instant::now() + RELOAD_RELEASE_TIMEOUT;
+    while (Arc::strong_count(&old) > 2 || old.hardware_shared()) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let res = if Arc::strong_count(&old) > 2 || old.hardware_shared() {
+        warn!("Not reloading, slots are still in use");
+        DropErrorRes {
+            error: "Slots are still in use, try the reload again shortly".to_string(),
+            errorCode: 503,
+            retry_after: Some(Utc::now() + RELOAD_RELEASE_TIMEOUT),
+            details: Vec::new(),
+        }
+        .respond()
+    } else {
+        if let Err(err) = machine::drop::stop_all_motors(&old) {
+            warn!("Reloading even though some motors didn't stop: {}", err);
+        }
+        let previous = settings.clone();
+        let app_data = data.clone();
+        let swapped = web::block(move || {
+            swap_config(&app_data, old, &new_settings, &previous)
+                .map(|total_slots| (total_slots, new_settings))
+        })
+        .await
+        // Nothing ran, so the old config is still in
+        .unwrap_or_else(|err| {
+            Err(ReloadFailure {
+                error: err.to_string(),
+                restored: true,
+            })
+        });
+        match swapped {
+            Ok((total_slots, new_settings)) => {
+                info!("Config reloaded with {} slots", total_slots);
+                *settings = new_settings;
+                // Slot numbers may point at different hardware now
+                for state in slot_stats.lock().await.values_mut() {
+                    state.last_stocked = None;
+                }
+                HttpResponse::Ok().json(ReloadResponse { total_slots })
+            }
+            Err(failure) => {
+                if failure.restored {
+                    error!("Config reload failed, kept the old one: {}", failure.error);
+                } else {
+                    error!(
+                        "Config reload failed and the old one wouldn't come back, taking the machine offline: {}",
+                        failure.error
+                    );
+                    data.offline.store(true, Ordering::SeqCst);
+                }
+                DropErrorRes {
+                    error: format!("Config reload failed: {}", failure.error),
+                    errorCode: 500,
+                    retry_after: None,
+                    details: Vec::new(),
+                }
+                .respond()
+            }
+        }
+    };
+    *monitors = Some(Monitors::start(data.clone(), slot_stats.clone()));
+    data.reloading.store(false, Ordering::SeqCst);
+    res
+}
+
+#[utoipa::path(
+    params(("fields" = Option<String>, Query, description = "Comma separated sections to include: health, config, stats, temperature_history, recent_drops")),
+    responses((status = 200, description = "Combined machine state", body = MachineState))
+)]
+#[get("/machine/state")]
+async fn machine_state(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    query: web::Query<MachineStateQuery>,
+) -> impl Responder {
+    let fields: Option<Vec<&str>> = query
+        .fields
+        .as_deref()
+        .map(|fields| fields.split(',').map(str::trim).collect());
+    HttpResponse::Ok().json(build_machine_state(&data, &slot_stats, fields.as_deref()).await)
+}
+
+/// Only does the work for the sections in `fields`, or all of them for `None`
+async fn build_machine_state(
+    data: &AppData,
+    slot_stats: &SlotStatsRegistry,
+    fields: Option<&[&str]>,
+) -> MachineState {
+    let wants = |section: &str| fields.is_none_or(|fields| fields.contains(&section));
+
+    let drop_in_progress = data.drop_in_progress.load(Ordering::SeqCst);
+    let config = data.config();
+    let health_report = if wants("health") {
+        let slots = status::get_slots_old(config.deref(), &*slot_stats.lock().await);
+        Some(HealthReport {
+            slots,
+            temp: read_temperature(data, config.deref()).await,
+            offline: data.offline.load(Ordering::SeqCst),
+            drop_in_progress,
+        })
+    } else {
+        None
+    };
+    let config_summary = wants("config").then(|| ConfigSummary {
+        slots: config.slots.iter().map(ToString::to_string).collect(),
+        temperature_id: config.temperature_id.clone(),
+        drop_delay_ms: config.drop_delay.as_millis() as u64,
+        allow_remote_reboot: config.allow_remote_reboot,
+        latch: config.latch_healthy().is_some(),
+        dry_run: data.dry_run,
+    });
+    std::mem::drop(config);
+
+    let history = data.history.lock().await;
+    MachineState {
+        health: health_report,
+        config: config_summary,
+        stats: wants("stats").then_some(history.stats),
+        temperature_history: wants("temperature_history").then(|| history.temperatures()),
+        recent_drops: wants("recent_drops").then(|| history.drops()),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 202, description = "Restarting", body = RebootResponse),
+        (status = 401, description = "Missing or invalid API key", body = DropErrorRes),
+        (status = 403, description = "Remote reboot is disabled, or BUB_API_KEY isn't set", body = DropErrorRes),
+    )
+)]
+#[post("/machine/reboot")]
+async fn reboot(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    let config = data.config();
+    if let Err(res) = require_api_key(&req, &config) {
+        return res.respond();
+    }
+    let source = req
+        .extensions()
+        .get::<ClientIp>()
+        .map(|client_ip| client_ip.0.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    if !config.allow_remote_reboot {
+        warn!(
+            "Refusing remote reboot from {}: BUB_ALLOW_REMOTE_REBOOT isn't set",
+            source
+        );
+        return HttpResponse::Forbidden().json(DropErrorRes {
+            error: "Remote reboot is disabled".to_string(),
+            errorCode: 403,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    info!(
+        "Reboot requested by {} at {}",
+        source,
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    );
+    // Holding the drop lock means any in-flight drop has finished, and
+    // going offline keeps new ones out while we shut down
+    let _drop_lock = data.drop_lock.lock().await;
+    data.offline.store(true, Ordering::SeqCst);
+    if let Err(err) = machine::drop::stop_all_motors(config.deref()) {
+        warn!("Rebooting even though some motors didn't stop: {}", err);
+    }
+    // Give actix a moment to send the response, then let systemd restart us
+    tokio::spawn(async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        info!("Exiting for remote reboot");
+        std::process::exit(0);
+    });
+    HttpResponse::Accepted().json(RebootResponse {
+        message: "Rebooting".to_string(),
+    })
+}
+
+#[utoipa::path(
+    responses((status = 200, description = "Scheduler and latch state", body = Diagnostics))
+)]
+#[get("/diagnostics")]
+async fn diagnostics(data: web::Data<AppData>) -> impl Responder {
+    let policy = RealtimeGuard::current_policy()
+        .map_err(|err| warn!("Couldn't read scheduler policy: {}", err))
+        .ok();
+    let priority = RealtimeGuard::current_priority()
+        .map_err(|err| warn!("Couldn't read scheduler priority: {}", err))
+        .ok();
+    let config = data.config();
+    let latch_healthy = config.latch_healthy();
+    let motor_fault_pins = config
+        .slots
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, slot_config)| {
+            let (pin, raised) = machine::drop::motor_fault_pin(slot_config)?;
+            Some(MotorFaultPin { slot, pin, raised })
+        })
+        .collect();
+    std::mem::drop(config);
+
+    HttpResponse::Ok().json(Diagnostics {
+        scheduler: SchedulerDiagnostics {
+            policy,
+            priority,
+            realtime_priority: REALTIME_PRIORITY,
+            realtime_capable: data.realtime_capable,
+        },
+        latch_healthy,
+        threads: debug::thread_count(),
+        tokio_tasks: debug::tokio_tasks(),
+        motor_fault_pins,
+    })
+}
+
+#[get("/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[get("/swagger-ui")]
+async fn swagger_ui() -> impl Responder {
+    // Everything the page needs is baked into the binary, the browser looking
+    // at it may be on the same isolated network as the machine
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI)
+}
+
+#[get("/swagger-ui/swagger-ui.css")]
+async fn swagger_ui_css() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/css; charset=utf-8")
+        .body(SWAGGER_UI_CSS)
+}
+
+#[get("/swagger-ui/swagger-ui-bundle.js")]
+async fn swagger_ui_bundle() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/javascript; charset=utf-8")
+        .body(SWAGGER_UI_BUNDLE)
+}
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<title>Bubbler API</title>
+<link rel="stylesheet" href="swagger-ui/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="swagger-ui/swagger-ui-bundle.js"></script>
+<script>
+SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+</script>
+</body>
+</html>
+"##;
+
+// swagger-ui-dist 5.17.14, see static/swagger-ui/LICENSE
+const SWAGGER_UI_CSS: &str = include_str!("../static/swagger-ui/swagger-ui.css");
+const SWAGGER_UI_BUNDLE: &str = include_str!("../static/swagger-ui/swagger-ui-bundle.js");
@@ -0,0 +1,69 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Proxies from `BUB_TRUSTED_PROXIES` whose `X-Forwarded-For` we believe
+#[derive(Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpNet>);
+
+/// Who actually sent the request, with any trusted proxies seen through.
+/// Put in the request extensions by `resolve_client_ip`.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl TrustedProxies {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Walks `X-Forwarded-For` back from the proxy that connected to us,
+    /// stopping at the first address we don't trust to have told the truth
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for
+            .into_iter()
+            .flat_map(|header| header.rsplit(','))
+        {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                // Garbage in the header, so don't believe anything past it
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    pub fn client_ip_for(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|header| header.to_str().ok());
+        Some(self.client_ip(peer, forwarded_for))
+    }
+}
+
+/// Middleware that works out the `ClientIp` for every request
+pub async fn resolve_client_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let client_ip = req
+        .app_data::<web::Data<TrustedProxies>>()
+        .and_then(|proxies| proxies.client_ip_for(&req));
+    if let Some(client_ip) = client_ip {
+        req.extensions_mut().insert(ClientIp(client_ip));
+    }
+    next.call(req).await
+}
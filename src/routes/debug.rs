@@ -0,0 +1,207 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use std::fs;
+use std::io;
+#[cfg(feature = "pprof")]
+use std::time::Duration;
+use tracing::warn;
+#[cfg(feature = "pprof")]
+use tracing::{error, info};
+
+use super::{require_api_key, AppData, DropErrorRes};
+
+/// How long `/debug/pprof` samples for
+#[cfg(feature = "pprof")]
+const PROFILE_DURATION: Duration = Duration::from_secs(5);
+
+/// Registers whichever debug endpoints were compiled in
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(memory).service(thread_list);
+    #[cfg(feature = "pprof")]
+    cfg.service(profile);
+}
+
+#[derive(Serialize)]
+struct MemoryUsage {
+    /// `None` when we aren't linked against glibc's malloc
+    heap_used_bytes: Option<u64>,
+    heap_allocated_bytes: Option<u64>,
+    system_rss_bytes: Option<u64>,
+}
+
+/// glibc's (used, allocated) heap bytes. `mallinfo` rather than `mallinfo2`
+/// so we still run on the older glibc some Pis have, which means the numbers
+/// wrap past 4GiB. We'll have bigger problems by then.
+#[cfg(target_env = "gnu")]
+fn heap_usage() -> Option<(u64, u64)> {
+    let info = unsafe { libc::mallinfo() };
+    let mmapped = info.hblkhd as u32 as u64;
+    Some((
+        info.uordblks as u32 as u64 + mmapped,
+        info.arena as u32 as u64 + mmapped,
+    ))
+}
+
+#[cfg(not(target_env = "gnu"))]
+fn heap_usage() -> Option<(u64, u64)> {
+    None
+}
+
+/// `VmRSS` from `/proc/self/status`
+fn rss_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status")
+        .map_err(|err| warn!("Couldn't read /proc/self/status: {}", err))
+        .ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// `BUB_ENABLE_DEBUG` and the API key, which every debug endpoint but the
+/// profiler needs
+async fn check_debug_enabled(req: &HttpRequest, data: &AppData) -> Result<(), DropErrorRes> {
+    let config = data.config();
+    if !config.enable_debug {
+        return Err(DropErrorRes {
+            error: "Debug endpoints are disabled, set BUB_ENABLE_DEBUG=1".to_string(),
+            errorCode: 404,
+            retry_after: None,
+            details: Vec::new(),
+        });
+    }
+    require_api_key(req, &config)
+}
+
+#[get("/debug/memory")]
+async fn memory(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    if let Err(res) = check_debug_enabled(&req, &data).await {
+        return res.respond();
+    }
+
+    let heap = heap_usage();
+    HttpResponse::Ok().json(MemoryUsage {
+        heap_used_bytes: heap.map(|(used, _)| used),
+        heap_allocated_bytes: heap.map(|(_, allocated)| allocated),
+        system_rss_bytes: rss_bytes(),
+    })
+}
+
+#[derive(Serialize)]
+struct ThreadInfo {
+    tid: u32,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct ThreadList {
+    threads: Vec<ThreadInfo>,
+    /// Tasks on the runtime of whichever actix worker answered
+    tokio_tasks: usize,
+}
+
+/// Every thread in the process, from `/proc/self/task`
+fn list_threads() -> io::Result<Vec<ThreadInfo>> {
+    let mut threads = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // The thread may have exited since we listed the directory
+        let name = fs::read_to_string(entry.path().join("comm"))
+            .map(|name| name.trim_end().to_string())
+            .unwrap_or_default();
+        threads.push(ThreadInfo { tid, name });
+    }
+    threads.sort_by_key(|thread| thread.tid);
+    Ok(threads)
+}
+
+pub(super) fn thread_count() -> Option<usize> {
+    list_threads()
+        .map_err(|err| warn!("Couldn't list threads: {}", err))
+        .ok()
+        .map(|threads| threads.len())
+}
+
+pub(super) fn tokio_tasks() -> usize {
+    tokio::runtime::Handle::current()
+        .metrics()
+        .num_alive_tasks()
+}
+
+#[get("/debug/threads")]
+async fn thread_list(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    if let Err(res) = check_debug_enabled(&req, &data).await {
+        return res.respond();
+    }
+
+    match list_threads() {
+        Ok(threads) => HttpResponse::Ok().json(ThreadList {
+            threads,
+            tokio_tasks: tokio_tasks(),
+        }),
+        Err(err) => HttpResponse::InternalServerError().json(DropErrorRes {
+            error: format!("Couldn't list threads: {}", err),
+            errorCode: 500,
+            retry_after: None,
+            details: Vec::new(),
+        }),
+    }
+}
+
+/// Profiles the whole process for `PROFILE_DURATION` and returns a flamegraph
+#[cfg(feature = "pprof")]
+#[get("/debug/pprof")]
+async fn profile(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
+    {
+        let config = data.config();
+        if !config.enable_pprof {
+            return HttpResponse::NotFound().json(DropErrorRes {
+                error: "Profiling is disabled, set BUB_ENABLE_PPROF=1".to_string(),
+                errorCode: 404,
+                retry_after: None,
+                details: Vec::new(),
+            });
+        }
+        if let Err(res) = require_api_key(&req, &config) {
+            return res.respond();
+        }
+    }
+
+    let profile_error = |err: pprof::Error| {
+        error!("Profiling failed: {}", err);
+        HttpResponse::InternalServerError().json(DropErrorRes {
+            error: format!("Profiling failed: {}", err),
+            errorCode: 500,
+            retry_after: None,
+            details: Vec::new(),
+        })
+    };
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(100)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(err) => return profile_error(err),
+    };
+    info!("Profiling for {:?}", PROFILE_DURATION);
+    tokio::time::sleep(PROFILE_DURATION).await;
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(err) => return profile_error(err),
+    };
+    let mut svg = Vec::new();
+    if let Err(err) = report.flamegraph(&mut svg) {
+        return profile_error(err);
+    }
+    HttpResponse::Ok().content_type("image/svg+xml").body(svg)
+}
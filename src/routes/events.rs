@@ -0,0 +1,19 @@
+use serde::Serialize;
+
+use super::history::DropRecord;
+use super::machine::status::SlotStatus;
+
+/// How many events a slow websocket client can fall behind before it starts
+/// missing some
+pub const EVENT_BUFFER: usize = 64;
+
+/// Things that happen to the machine, pushed to websocket clients as they
+/// happen
+#[derive(Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MachineEvent {
+    DropStarted { slot: usize },
+    DropCompleted(DropRecord),
+    SlotChanged(SlotStatus),
+    TemperatureUpdated { temp: Option<f32> },
+}
@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use utoipa::ToSchema;
+
+/// How many temperature readings to hang on to
+pub const TEMPERATURE_HISTORY_LEN: usize = 5;
+/// How many drops to hang on to
+pub const RECENT_DROPS_LEN: usize = 10;
+/// How many stock changes to hang on to
+pub const STOCK_EVENTS_LEN: usize = 100;
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct TemperatureReading {
+    pub temp: f32,
+    pub timestamp: String,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct DropRecord {
+    pub slot: usize,
+    pub success: bool,
+    /// The error message for failed drops
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub timestamp: String,
+    /// Whatever the client sent as `metadata` with the drop
+    #[schema(value_type = Option<Object>)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Default, Serialize, ToSchema)]
+pub struct DropStats {
+    pub total_drops: u64,
+    pub failed_drops: u64,
+}
+
+/// Recent goings-on, kept in memory only so it resets on restart
+#[derive(Default)]
+pub struct History {
+    pub stats: DropStats,
+    /// When the most recent drop started
+    pub last_drop_at: Option<DateTime<Utc>>,
+    temperatures: VecDeque<TemperatureReading>,
+    drops: VecDeque<DropRecord>,
+}
+
+#[derive(Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CamPulseResult {
+    Success,
+    Timeout,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct CamPulseRecord {
+    pub drop_at: String,
+    pub pulse_ms: u64,
+    pub result: CamPulseResult,
+}
+
+pub(crate) fn push_bounded<T>(queue: &mut VecDeque<T>, item: T, len: usize) {
+    queue.push_back(item);
+    while queue.len() > len {
+        queue.pop_front();
+    }
+}
+
+impl History {
+    pub fn record_temperature(&mut self, reading: TemperatureReading) {
+        push_bounded(&mut self.temperatures, reading, TEMPERATURE_HISTORY_LEN);
+    }
+
+    pub fn record_drop(&mut self, record: DropRecord, at: DateTime<Utc>) {
+        self.last_drop_at = Some(at);
+        self.stats.total_drops += 1;
+        if !record.success {
+            self.stats.failed_drops += 1;
+        }
+        push_bounded(&mut self.drops, record, RECENT_DROPS_LEN);
+    }
+
+    pub fn last_temperature(&self) -> Option<&TemperatureReading> {
+        self.temperatures.back()
+    }
+
+    /// Oldest first
+    pub fn temperatures(&self) -> Vec<TemperatureReading> {
+        self.temperatures.iter().cloned().collect()
+    }
+
+    /// Oldest first
+    pub fn drops(&self) -> Vec<DropRecord> {
+        self.drops.iter().cloned().collect()
+    }
+
+    /// Forgets the recorded drops, only the ones from before `before` if
+    /// it's given, and says how many went. The stats keep counting.
+    pub fn clear_drops(&mut self, before: Option<DateTime<Utc>>) -> usize {
+        let len = self.drops.len();
+        match before {
+            None => self.drops.clear(),
+            Some(before) => self.drops.retain(|record| {
+                DateTime::parse_from_rfc3339(&record.timestamp)
+                    .map_or(true, |timestamp| timestamp >= before)
+            }),
+        }
+        len - self.drops.len()
+    }
+
+    /// How many drops are recorded right now
+    pub fn drop_count(&self) -> usize {
+        self.drops.len()
+    }
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct StockEvent {
+    pub slot: usize,
+    pub slot_id: String,
+    /// Whether the slot became stocked or empty
+    pub changed_to: bool,
+    pub detected_at: String,
+}
+
+/// Slots filling up and emptying, kept apart from the drop history since
+/// restocking has nothing to do with vending
+#[derive(Default)]
+pub struct StockEventLog {
+    events: VecDeque<StockEvent>,
+}
+
+impl StockEventLog {
+    pub fn record(&mut self, event: StockEvent) {
+        push_bounded(&mut self.events, event, STOCK_EVENTS_LEN);
+    }
+
+    /// Oldest first
+    pub fn events(&self) -> Vec<StockEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,845 @@
+use crate::scheduler::RealtimeGuard;
+#[cfg(feature = "gpio")]
+use futures::stream::StreamExt;
+#[cfg(feature = "gpio")]
+use gpio_cdev::{EventRequestFlags, Line, LineRequestFlags};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::status::is_stocked;
+#[cfg(feature = "owfs")]
+use super::status::read_owfs_byte;
+use crate::config::{ConfigData, SlotConfig, SlotConfig::*};
+#[cfg(any(test, feature = "mock"))]
+use crate::mock::MockSlot;
+use std::fmt::{self, Debug, Display, Formatter};
+#[cfg(feature = "owfs")]
+use std::fs;
+#[cfg(feature = "owfs")]
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+
+#[derive(Debug)]
+pub enum DropState {
+    Success,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DropMethod {
+    CamDetected,
+    TimedDelay,
+    OwfsDoubleOff,
+}
+
+impl Display for DropError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MotorFailed => write!(f, "Motor didn't actuate"),
+            Self::MotorTimeout { waited_ms } => {
+                write!(f, "Motor timed out after {}ms. Is it stuck?", waited_ms)
+            }
+            Self::BadSlot { slot, max_slot } => {
+                write!(f, "Invalid slot {} (machine has {} slots)", slot, max_slot)
+            }
+            Self::MotorFault => write!(f, "Motor driver reported a fault. Is it jammed?"),
+            Self::Cancelled => write!(f, "Drop was cancelled by an emergency stop"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DropError {
+    MotorFailed,
+    /// The cam didn't come around within `waited_ms`
+    MotorTimeout {
+        waited_ms: u64,
+    },
+    /// `slot` isn't below `max_slot`, the number of slots
+    BadSlot {
+        slot: usize,
+        max_slot: usize,
+    },
+    /// The driver's fault pin went high, so we cut the motor early
+    MotorFault,
+    /// `DropOptions::cancel` fired while we were waiting on the cam
+    Cancelled,
+}
+
+// None of the variants wrap another error yet, so the default `source()` is right
+impl std::error::Error for DropError {}
+
+impl DropError {
+    /// The HTTP status to answer with: the client's fault or ours
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::BadSlot { .. } => 422,
+            Self::MotorFailed | Self::MotorTimeout { .. } | Self::MotorFault => 500,
+            Self::Cancelled => 503,
+        }
+    }
+
+    /// Numbers worth sending along with the message, as `(field, value)`
+    pub fn details(&self) -> Vec<(&'static str, u64)> {
+        match self {
+            Self::MotorTimeout { waited_ms } => vec![("waited_ms", *waited_ms)],
+            Self::BadSlot { slot, max_slot } => {
+                vec![("slot", *slot as u64), ("max_slot", *max_slot as u64)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// How long the cam took to come back around
+#[derive(Debug, Clone, Copy)]
+pub struct CamPulse {
+    pub duration: Duration,
+    pub timed_out: bool,
+}
+
+/// When each step of a successful drop happened
+#[derive(Debug, Clone)]
+pub struct DropResult {
+    pub slot: usize,
+    /// The slot's hardware address, as shown in `SlotStatus.id`
+    pub slot_id: String,
+    pub method: DropMethod,
+    /// Whether the latch was there and opened without complaint
+    pub latch_opened: bool,
+    pub motor_on_at: Instant,
+    /// The first time we turned it off, OWFS slots get a second go later
+    pub motor_off_at: Instant,
+    /// Only for slots with a cam, and never for dry runs
+    pub cam_start_at: Option<Instant>,
+    pub cam_stop_at: Option<Instant>,
+}
+
+impl DropResult {
+    /// How long the motor was on for
+    pub fn motor_duration(&self) -> Duration {
+        self.motor_off_at.duration_since(self.motor_on_at)
+    }
+
+    /// How long the cam took to come back around
+    pub fn cam_duration(&self) -> Option<Duration> {
+        Some(self.cam_stop_at?.duration_since(self.cam_start_at?))
+    }
+}
+
+#[derive(Debug)]
+pub struct DropOutcome {
+    pub result: Result<DropResult, DropError>,
+    /// Only set for slots with a cam, and never for dry runs
+    pub cam_pulse: Option<CamPulse>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DropOptions {
+    /// Go through the whole drop without actually switching the motor
+    pub dry_run: bool,
+    /// Who asked for the drop, for the logs
+    pub operator_id: Option<String>,
+    /// The request's `X-Request-Id`, for the logs
+    pub request_id: Option<String>,
+    /// Don't read the slot's stocked switch first. Empty slots get dropped
+    /// from either way, the read only decides whether to warn about it.
+    pub skip_stocked_check: bool,
+    /// Use this instead of reading the slot's stocked switch
+    pub stocked_override: Option<bool>,
+    /// Overrides `ConfigData::drop_delay` for this drop
+    pub custom_delay_ms: Option<u64>,
+    /// Stops waiting on the cam early when cancelled
+    pub cancel: Option<CancellationToken>,
+}
+
+/// `slot_index` is `slot`'s index in `config.slots`, only for the logs
+pub fn run_motor(
+    // Only OWFS has anything to tune
+    #[cfg_attr(not(feature = "owfs"), allow(unused_variables))] config: &ConfigData,
+    slot_index: usize,
+    slot: &SlotConfig,
+    state: bool,
+) -> Result<DropState, DropError> {
+    #[cfg(any(feature = "owfs", feature = "gpio"))]
+    let num_state = match state {
+        true => 1,
+        false => 0,
+    };
+    let motor_okay: Result<(), String> = match slot {
+        #[cfg(feature = "owfs")]
+        OWFS(slot_id) => write_pio(
+            &format!("/mnt/w1/{}/PIO", slot_id),
+            num_state,
+            config.owfs_verify_retry,
+        ),
+        #[cfg(feature = "owfs")]
+        OwfsGpio { device_id, channel } => {
+            write_pio_channel(device_id, *channel, state, config.owfs_verify_retry)
+        }
+        #[cfg(feature = "owfs")]
+        OwfsRedundant { primary, backup } => {
+            // Either device can run the motor on its own, so only give up if
+            // neither took the write
+            let write = |id: &str| {
+                write_pio(
+                    &format!("/mnt/w1/{}/PIO", id),
+                    num_state,
+                    config.owfs_verify_retry,
+                )
+            };
+            match (write(primary), write(backup)) {
+                (Err(primary_err), Err(backup_err)) => {
+                    Err(format!("primary: {}, backup: {}", primary_err, backup_err))
+                }
+                (Err(err), Ok(())) | (Ok(()), Err(err)) => {
+                    warn!(
+                        "One of slot {}'s redundant devices ({}) failed: {}",
+                        slot_index, slot, err
+                    );
+                    Ok(())
+                }
+                (Ok(()), Ok(())) => Ok(()),
+            }
+        }
+        #[cfg(feature = "gpio")]
+        GPIO { vend, .. } => vend
+            .set_value(num_state)
+            .map_err(|err| format!("{:?}", err)),
+        #[cfg(any(test, feature = "mock"))]
+        Mock(mock) => {
+            mock.set_motor(state);
+            Ok(())
+        }
+    };
+    match motor_okay {
+        Err(err) => {
+            error!(
+                "Error turning motor {} for slot {} ({}): {}",
+                if state { "on" } else { "off" },
+                slot_index,
+                slot,
+                err
+            );
+            Err(DropError::MotorFailed)
+        }
+        Ok(_) => Ok(DropState::Success),
+    }
+}
+
+/// OWFS happily accepts writes that never make it onto the bus, so read the
+/// PIO back and write again until it sticks or we run out of `attempts`.
+/// 0 attempts trusts the first write.
+#[cfg(feature = "owfs")]
+fn write_pio(path: &str, value: u8, attempts: u32) -> Result<(), String> {
+    fs::write(path, value.to_string()).map_err(|err| format!("{:?}", err))?;
+    for attempt in 1..=attempts {
+        match fs::read_to_string(path) {
+            Ok(read) if read.trim() == value.to_string() => return Ok(()),
+            Ok(read) => warn!(
+                "{} read back {:?} after writing {} (attempt {}/{})",
+                path,
+                read.trim(),
+                value,
+                attempt,
+                attempts
+            ),
+            Err(err) => warn!(
+                "Couldn't read back {} (attempt {}/{}): {:?}",
+                path, attempt, attempts, err
+            ),
+        }
+        if attempt < attempts {
+            fs::write(path, value.to_string()).map_err(|err| format!("{:?}", err))?;
+        }
+    }
+    if attempts == 0 {
+        return Ok(());
+    }
+    Err(format!(
+        "PIO readback mismatch for {} after {} attempts",
+        path, attempts
+    ))
+}
+
+/// Flips one DS2408 channel, leaving the other seven as they were
+#[cfg(feature = "owfs")]
+fn write_pio_channel(
+    device_id: &str,
+    channel: u8,
+    state: bool,
+    attempts: u32,
+) -> Result<(), String> {
+    let path = format!("/mnt/w1/{}/PIO.BYTE", device_id);
+    let current = read_owfs_byte(&path).map_err(|err| format!("{:?}", err))?;
+    let mask = 1 << channel;
+    let value = if state {
+        current | mask
+    } else {
+        current & !mask
+    };
+    write_pio(&path, value, attempts)
+}
+
+/// Only the motor write itself runs with real-time priority. Holding the
+/// guard across the cam wait or drop delay buys nothing, since those yield
+/// or sleep anyway.
+fn run_motor_realtime(
+    config: &ConfigData,
+    slot_index: usize,
+    slot: &SlotConfig,
+    state: bool,
+) -> Result<DropState, DropError> {
+    // Pretend motors have no timing to protect, and the laptops they run on
+    // rarely let us have real-time priority
+    #[cfg(any(test, feature = "mock"))]
+    if matches!(slot, Mock(_)) {
+        return run_motor(config, slot_index, slot, state);
+    }
+    let _rt = RealtimeGuard::with_policy(config.rt_policy);
+    run_motor(config, slot_index, slot, state)
+}
+
+pub fn stop_all_motors(config: &ConfigData) -> Result<(), DropError> {
+    let mut result = Ok(());
+    for (index, slot) in config.slots.iter().enumerate() {
+        if let Err(err) = run_motor(config, index, slot, false) {
+            error!(
+                "Couldn't turn off motor for slot {} ({})! {:?}",
+                index, slot, err
+            );
+            result = Err(err);
+        }
+    }
+    result
+}
+
+#[cfg(feature = "gpio")]
+async fn wait_until_line_hits_value(
+    line: &Line,
+    edge: EventRequestFlags,
+    timeout: Duration,
+    cancel: Option<&CancellationToken>,
+) -> Result<(), DropError> {
+    let mut event_handle = line
+        .async_events(LineRequestFlags::INPUT, edge, "bub-cam-events")
+        .map_err(|err| {
+            error!("Couldn't watch cam pin {}: {}", line.offset(), err);
+            DropError::MotorFailed
+        })?;
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = event_handle.next() => Ok(()),
+        () = tokio::time::sleep(timeout) => Err(DropError::MotorTimeout {
+            waited_ms: timeout.as_millis() as u64,
+        }),
+        () = cancelled => Err(DropError::Cancelled),
+    }
+}
+
+/// How long a cam gets to finish its turn before we call the motor stuck
+#[cfg(any(feature = "gpio", test, feature = "mock"))]
+const CAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits for a full turn of the slot's cam, or returns `None` if the slot
+/// doesn't have one. Also returns how long the cam was up for.
+async fn wait_for_cam(
+    slot: &SlotConfig,
+    // Only GPIO and mock slots have cams
+    #[cfg_attr(
+        not(any(feature = "gpio", test, feature = "mock")),
+        allow(unused_variables)
+    )]
+    cancel: Option<&CancellationToken>,
+) -> Option<(Result<(), DropError>, Duration)> {
+    match slot {
+        #[cfg(feature = "gpio")]
+        GPIO { cam: Some(cam), .. } => Some(wait_for_gpio_cam(cam, cancel).await),
+        #[cfg(any(test, feature = "mock"))]
+        Mock(mock) => Some(wait_for_mock_cam(mock, cancel).await),
+        #[cfg(any(feature = "owfs", feature = "gpio"))]
+        _ => None,
+    }
+}
+
+#[cfg(feature = "gpio")]
+async fn wait_for_gpio_cam(
+    cam: &Line,
+    cancel: Option<&CancellationToken>,
+) -> (Result<(), DropError>, Duration) {
+    info!("Waiting for motor to start rotating...",);
+    match wait_until_line_hits_value(
+        cam,
+        EventRequestFlags::RISING_EDGE,
+        Duration::from_millis(500),
+        cancel,
+    )
+    .await
+    {
+        Err(DropError::Cancelled) => return (Err(DropError::Cancelled), Duration::ZERO),
+        Err(err) => warn!("Were we already been spinning? {err:?}"),
+        Ok(()) => {}
+    }
+    info!("Waiting for motor to stop rotating...");
+    let started = Instant::now();
+    let result =
+        wait_until_line_hits_value(cam, EventRequestFlags::FALLING_EDGE, CAM_TIMEOUT, cancel).await;
+    info!("Motor stopped rotating!",);
+    (result, started.elapsed())
+}
+
+/// Pretends to be a cam, which only goes round while the motor's on
+#[cfg(any(test, feature = "mock"))]
+async fn wait_for_mock_cam(
+    slot: &MockSlot,
+    cancel: Option<&CancellationToken>,
+) -> (Result<(), DropError>, Duration) {
+    let started = Instant::now();
+    let (turn, result) = match slot.motor_on() {
+        true => (slot.cam_time, Ok(())),
+        false => (
+            CAM_TIMEOUT,
+            Err(DropError::MotorTimeout {
+                waited_ms: CAM_TIMEOUT.as_millis() as u64,
+            }),
+        ),
+    };
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::select! {
+        () = tokio::time::sleep(turn) => (result, started.elapsed()),
+        () = cancelled => (Err(DropError::Cancelled), Duration::ZERO),
+    }
+}
+
+/// Keeps re-opening the latch so its watchdog doesn't shut it mid-drop.
+/// Never finishes.
+#[cfg(feature = "gpio")]
+async fn keep_latch_open(config: &ConfigData) {
+    let latch = match config.latch.as_ref() {
+        Some(latch) => latch,
+        None => return futures::future::pending().await,
+    };
+    // interval() panics on zero, and a zero watchdog is hopeless anyway
+    let mut heartbeat = tokio::time::interval((latch.watchdog() / 2).max(Duration::from_millis(1)));
+    // The first tick is immediate, and the drop only just opened it
+    heartbeat.tick().await;
+    loop {
+        heartbeat.tick().await;
+        if let Err(err) = latch.open() {
+            warn!("Problem keeping latch open: {}", err);
+        }
+    }
+}
+
+#[cfg(not(feature = "gpio"))]
+async fn keep_latch_open(_config: &ConfigData) {
+    futures::future::pending().await
+}
+
+/// Resolves if the slot's motor driver flags a fault, never for slots
+/// without a fault pin
+#[cfg(feature = "gpio")]
+async fn wait_for_motor_fault(slot: &SlotConfig) {
+    let fault = match slot {
+        GPIO {
+            fault: Some(fault), ..
+        } => fault,
+        _ => return futures::future::pending().await,
+    };
+    let mut events = match fault.async_events(
+        LineRequestFlags::INPUT,
+        EventRequestFlags::RISING_EDGE,
+        "bub-fault-events",
+    ) {
+        Ok(events) => events,
+        Err(err) => {
+            warn!("Can't watch motor fault pin for {}: {}", slot, err);
+            return futures::future::pending().await;
+        }
+    };
+    events.next().await;
+}
+
+#[cfg(not(feature = "gpio"))]
+async fn wait_for_motor_fault(_slot: &SlotConfig) {
+    futures::future::pending().await
+}
+
+/// The slot's motor fault pin and whether it's raised right now, `None` for
+/// slots without one
+#[cfg(feature = "gpio")]
+pub fn motor_fault_pin(slot: &SlotConfig) -> Option<(u32, Option<bool>)> {
+    let fault = match slot {
+        GPIO {
+            fault: Some(fault), ..
+        } => fault,
+        _ => return None,
+    };
+    let raised = fault
+        .request(LineRequestFlags::INPUT, 0, "bub-fault-check")
+        .and_then(|handle| handle.get_value())
+        .map(|value| value == 1)
+        .map_err(|err| warn!("Couldn't read motor fault pin for {}: {}", slot, err))
+        .ok();
+    Some((fault.offset(), raised))
+}
+
+#[cfg(not(feature = "gpio"))]
+pub fn motor_fault_pin(_slot: &SlotConfig) -> Option<(u32, Option<bool>)> {
+    None
+}
+
+/// Drops from `slot`, a 0-based index into `config.slots` (the same `number`
+/// reported by `get_slots`). `config` should be one `AppData::config()`
+/// snapshot, which nothing else waits on while the motor runs.
+pub async fn drop(config: &ConfigData, slot: usize, options: DropOptions) -> DropOutcome {
+    // Everything logged during the drop carries these, so concurrent drops
+    // can be told apart
+    let span = info_span!(
+        "drop",
+        slot,
+        request_id = options.request_id.as_deref().unwrap_or("-")
+    );
+    let mut cam_pulse = None;
+    let result = drop_inner(config, slot, options, &mut cam_pulse)
+        .instrument(span)
+        .await;
+    DropOutcome { result, cam_pulse }
+}
+
+async fn drop_inner(
+    config: &ConfigData,
+    slot: usize,
+    options: DropOptions,
+    cam_pulse: &mut Option<CamPulse>,
+) -> Result<DropResult, DropError> {
+    let slot_config = match config.get_slot(slot) {
+        Some(slot_config) => slot_config,
+        None => {
+            warn!("We were asked to drop an invalid slot {}: BadSlot!", slot);
+            return Err(DropError::BadSlot {
+                slot,
+                max_slot: config.slots.len(),
+            });
+        }
+    };
+    match options.operator_id.as_ref() {
+        Some(operator) => info!("Dropping {} for {}!", slot_config, operator),
+        None => info!("Dropping {}!", slot_config),
+    }
+
+    if !options.skip_stocked_check {
+        let stocked = match options.stocked_override {
+            Some(stocked) => Ok(stocked),
+            None => is_stocked(slot_config, config.stocked_inverted(slot)),
+        };
+        match stocked {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "Dropping {} ({}) even though it reads as empty",
+                slot, slot_config
+            ),
+            Err(err) => warn!(
+                "Couldn't tell if {} ({}) is stocked, dropping anyway: {:?}",
+                slot, slot_config, err
+            ),
+        }
+    }
+
+    let drop_delay = options
+        .custom_delay_ms
+        .map(Duration::from_millis)
+        .unwrap_or(config.drop_delay);
+    let actuate = |state: bool| {
+        if let Some(fault) = config.injected_fault(slot, state) {
+            warn!(
+                "Simulating motor failure for slot {} ({})",
+                slot, fault.operation
+            );
+            Err(DropError::MotorFailed)
+        } else if options.dry_run {
+            info!(
+                "Dry run, not turning motor {} for {}",
+                if state { "on" } else { "off" },
+                slot_config
+            );
+            Ok(DropState::Success)
+        } else {
+            run_motor_realtime(config, slot, slot_config, state)
+        }
+    };
+
+    let method = match slot_config {
+        #[cfg(feature = "owfs")]
+        OWFS(_) | OwfsGpio { .. } | OwfsRedundant { .. } => DropMethod::OwfsDoubleOff,
+        #[cfg(feature = "gpio")]
+        GPIO { cam: Some(_), .. } => DropMethod::CamDetected,
+        #[cfg(feature = "gpio")]
+        GPIO { cam: None, .. } => DropMethod::TimedDelay,
+        #[cfg(any(test, feature = "mock"))]
+        Mock(_) => DropMethod::CamDetected,
+    };
+    let mut result = Ok(method);
+    #[cfg(feature = "gpio")]
+    let latch_opened = match config.latch.as_ref() {
+        Some(latch) => match latch.open() {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Problem opening latch for {}: {}", slot_config, err);
+                false
+            }
+        },
+        None => false,
+    };
+    #[cfg(not(feature = "gpio"))]
+    let latch_opened = false;
+    let (mut cam_start_at, mut cam_stop_at) = (None, None);
+    let running = async {
+        let cam = wait_for_cam(slot_config, options.cancel.as_ref()).await;
+        if cam.is_none() {
+            info!("Sleeping for {:?} after dropping", drop_delay);
+            tokio::time::sleep(drop_delay).await;
+        }
+        cam
+    };
+    let motor_on_at = Instant::now();
+    if let Err(err) = actuate(true) {
+        error!("Problem dropping {} ({})! {:?}", slot, slot_config, err);
+        result = Err(err);
+    } else if let Some((cam_result, duration)) = tokio::select! {
+        cam = running => cam,
+        () = keep_latch_open(config) => unreachable!("the latch heartbeat never finishes"),
+        () = wait_for_motor_fault(slot_config) => {
+            error!("Motor fault on slot {} ({}), stopping it", slot, slot_config);
+            result = Err(DropError::MotorFault);
+            None
+        }
+    } {
+        match cam_result {
+            // The motor never turned, so of course the cam didn't either
+            Err(err) if options.dry_run => info!("Dry run, ignoring cam result: {}", err),
+            // We never saw the whole turn, so there's no pulse to record
+            Err(DropError::Cancelled) => {
+                warn!("Drop of {} ({}) cancelled, stopping it", slot, slot_config);
+                result = Err(DropError::Cancelled);
+            }
+            Err(err) => {
+                cam_start_at = Instant::now().checked_sub(duration);
+                *cam_pulse = Some(CamPulse {
+                    duration,
+                    timed_out: true,
+                });
+                result = Err(err);
+            }
+            Ok(()) => {
+                let stopped = Instant::now();
+                cam_start_at = stopped.checked_sub(duration);
+                cam_stop_at = Some(stopped);
+                *cam_pulse = Some(CamPulse {
+                    duration,
+                    timed_out: false,
+                })
+            }
+        }
+    }
+
+    info!("Shutting off motor for slot {} ({})", slot, slot_config);
+    let motor_off_at = Instant::now();
+    if let Err(err) = actuate(false) {
+        error!(
+            "Couldn't turn off motor for slot {} ({})! {:?}",
+            slot, slot_config, err
+        );
+        result = Err(err);
+    }
+
+    match slot_config {
+        #[cfg(feature = "owfs")]
+        OWFS(_) | OwfsGpio { .. } | OwfsRedundant { .. } => {
+            info!("Drop completed. Allowing another drop time to stop motors again.");
+            thread::sleep(drop_delay);
+
+            info!("Shutting off motor again to ensure it's safe");
+            if let Err(err) = actuate(false) {
+                error!(
+                    "Couldn't turn off motor [again] for slot {} ({})! {:?}",
+                    slot, slot_config, err
+                );
+                return Err(err);
+            }
+        }
+        #[cfg(feature = "gpio")]
+        GPIO { .. } => {
+            info!("Drop completed (GPIO drop, we trust the kernel)");
+        }
+        #[cfg(any(test, feature = "mock"))]
+        Mock(_) => {
+            info!("Drop completed (mock slot, nothing to trust)");
+        }
+    };
+
+    info!("Drop transaction finished with {:?}", result);
+
+    result.map(|method| DropResult {
+        slot,
+        slot_id: slot_config.to_string(),
+        method,
+        latch_opened,
+        motor_on_at,
+        motor_off_at,
+        cam_start_at,
+        cam_stop_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    fn mock_config(slots: &str) -> ConfigData {
+        stocked_mock_config(slots, "5")
+    }
+
+    /// `slots` mock slots with `stock` drinks in each
+    fn stocked_mock_config(slots: &str, stock: &str) -> ConfigData {
+        let settings = Settings::from_vars(&[
+            ("BUB_MOCK", "1"),
+            ("BUB_MOCK_SLOTS", slots),
+            ("BUB_MOCK_STOCK", stock),
+            ("BUB_MOCK_CAM_MS", "1"),
+        ]);
+        ConfigData::new(&settings).expect("mock config is valid")
+    }
+
+    fn slot_stocked(config: &ConfigData, slot: usize) -> bool {
+        is_stocked(config.get_slot(slot).expect("slot exists"), false).expect("mock slots read")
+    }
+
+    #[tokio::test]
+    async fn first_slot_is_zero() {
+        let config = mock_config("3");
+        let dropped = drop(&config, 0, DropOptions::default())
+            .await
+            .result
+            .expect("slot 0 exists");
+        assert_eq!(dropped.slot, 0);
+        assert_eq!(dropped.slot_id, "mock0");
+    }
+
+    #[tokio::test]
+    async fn last_slot_is_len_minus_one() {
+        let config = mock_config("3");
+        let dropped = drop(&config, 2, DropOptions::default())
+            .await
+            .result
+            .expect("slot 2 exists");
+        assert_eq!(dropped.slot, 2);
+        assert_eq!(dropped.slot_id, "mock2");
+    }
+
+    #[tokio::test]
+    async fn len_is_past_the_end() {
+        let config = mock_config("3");
+        let outcome = drop(&config, 3, DropOptions::default()).await;
+        assert!(matches!(
+            outcome.result,
+            Err(DropError::BadSlot {
+                slot: 3,
+                max_slot: 3
+            })
+        ));
+        assert!(outcome.cam_pulse.is_none());
+    }
+
+    #[tokio::test]
+    async fn no_slots_means_no_slot_zero() {
+        let config = mock_config("0");
+        let outcome = drop(&config, 0, DropOptions::default()).await;
+        assert!(matches!(
+            outcome.result,
+            Err(DropError::BadSlot {
+                slot: 0,
+                max_slot: 0
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dry_run_leaves_the_stock_alone() {
+        let config = stocked_mock_config("1", "1");
+        let options = DropOptions {
+            dry_run: true,
+            operator_id: Some("tester".to_string()),
+            ..DropOptions::default()
+        };
+        let outcome = drop(&config, 0, options).await;
+        assert!(outcome.result.is_ok());
+        // The motor never ran, so neither did the cam
+        assert!(outcome.cam_pulse.is_none());
+        assert!(slot_stocked(&config, 0));
+    }
+
+    #[tokio::test]
+    async fn empty_slots_still_drop() {
+        let config = stocked_mock_config("1", "0");
+        assert!(!slot_stocked(&config, 0));
+        let outcome = drop(&config, 0, DropOptions::default()).await;
+        assert!(outcome.result.is_ok());
+
+        let options = DropOptions {
+            skip_stocked_check: true,
+            ..DropOptions::default()
+        };
+        assert!(drop(&config, 0, options).await.result.is_ok());
+
+        let options = DropOptions {
+            stocked_override: Some(false),
+            ..DropOptions::default()
+        };
+        assert!(drop(&config, 0, options).await.result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn custom_delay_doesnt_stop_the_cam() {
+        let config = stocked_mock_config("1", "1");
+        let options = DropOptions {
+            custom_delay_ms: Some(1),
+            ..DropOptions::default()
+        };
+        let dropped = drop(&config, 0, options)
+            .await
+            .result
+            .expect("slot 0 drops");
+        // Slots with a cam wait on it rather than the delay
+        assert!(matches!(dropped.method, DropMethod::CamDetected));
+        assert!(!slot_stocked(&config, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_drop_stops_the_motor() {
+        let config = stocked_mock_config("1", "1");
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let options = DropOptions {
+            cancel: Some(cancel),
+            ..DropOptions::default()
+        };
+        let outcome = drop(&config, 0, options).await;
+        assert!(matches!(outcome.result, Err(DropError::Cancelled)));
+        // Turning the motor off again still lets one out
+        assert!(!slot_stocked(&config, 0));
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::{ConfigData, SlotConfig, SlotConfig::*};
+use crate::routes::slot_stats::{SlotMeta, SlotState};
+use std::collections::HashMap;
+#[cfg(feature = "owfs")]
+use std::fs;
+use std::io;
+use tracing::warn;
+
+/// Reads the slot's stocked switch, flipped if it's in
+/// `BUB_SLOT_STOCKED_INVERT`
+pub(crate) fn is_stocked(slot: &SlotConfig, inverted: bool) -> io::Result<bool> {
+    Ok(read_stocked(slot)? ^ inverted)
+}
+
+fn read_stocked(slot: &SlotConfig) -> io::Result<bool> {
+    match slot {
+        #[cfg(feature = "gpio")]
+        GPIO { stocked, .. } => Ok(stocked.get_value().map_err(io::Error::other)? == 1),
+        #[cfg(feature = "owfs")]
+        OWFS(id) => Ok(fs::File::open(format!("/mnt/w1/{}/id", id)).is_ok()),
+        #[cfg(feature = "owfs")]
+        OwfsGpio { device_id, channel } => {
+            let sensed = read_owfs_byte(&format!("/mnt/w1/{}/sensed.BYTE", device_id))?;
+            Ok(sensed & (1 << channel) != 0)
+        }
+        #[cfg(feature = "owfs")]
+        OwfsRedundant { primary, backup } => {
+            let present = |id: &str| fs::File::open(format!("/mnt/w1/{}/id", id)).is_ok();
+            Ok(present(primary) || present(backup))
+        }
+        #[cfg(any(test, feature = "mock"))]
+        Mock(mock) => Ok(mock.is_stocked()),
+    }
+}
+
+/// Reads one of OWFS's `.BYTE` files, which hold a padded decimal number
+#[cfg(feature = "owfs")]
+pub(crate) fn read_owfs_byte(path: &str) -> io::Result<u8> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, err)))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LegacySlotEntry {
+    pub number: usize,
+    pub id: String,
+    pub stocked: bool,
+}
+
+/// The slot's stocked status, unless an operator has overridden it
+fn stocked_status(
+    slot: &SlotConfig,
+    inverted: bool,
+    state: Option<&SlotState>,
+) -> (io::Result<bool>, StockedSource) {
+    match state.and_then(|state| state.stocked_override) {
+        Some(stocked) => (Ok(stocked), StockedSource::Override),
+        None => (is_stocked(slot, inverted), StockedSource::Hardware),
+    }
+}
+
+pub fn get_slots_old(
+    config: &ConfigData,
+    states: &HashMap<usize, SlotState>,
+) -> Vec<LegacySlotEntry> {
+    config
+        .slots
+        .iter()
+        .enumerate()
+        .map(|(number, slot)| LegacySlotEntry {
+            number,
+            id: slot.to_string(),
+            stocked: stocked_status(slot, config.stocked_inverted(number), states.get(&number))
+                .0
+                .unwrap_or_else(|err| {
+                    warn!("Couldn't read stocked state for {}: {:?}", slot, err);
+                    false
+                }),
+        })
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StockedSource {
+    Hardware,
+    Override,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SlotStatus {
+    pub id: String,
+    pub number: i32,
+    pub stocked: bool,
+    pub stocked_source: StockedSource,
+    pub error: Option<String>,
+    pub meta: SlotMeta,
+}
+
+pub fn get_slot(
+    config: &ConfigData,
+    states: &HashMap<usize, SlotState>,
+    number: usize,
+) -> Option<SlotStatus> {
+    let slot = config.get_slot(number)?;
+    let state = states.get(&number);
+    let (stocked, stocked_source) = stocked_status(slot, config.stocked_inverted(number), state);
+    let (stocked, error) = match stocked {
+        Ok(stocked) => (stocked, None),
+        Err(err) => (
+            false,
+            Some(format!("{} read failed: {}", slot.backend(), err)),
+        ),
+    };
+    Some(SlotStatus {
+        id: format!("{}", slot),
+        number: number as i32,
+        stocked,
+        stocked_source,
+        error,
+        meta: state.map(|state| state.meta.clone()).unwrap_or_default(),
+    })
+}
+
+pub fn get_slots(config: &ConfigData, states: &HashMap<usize, SlotState>) -> Vec<SlotStatus> {
+    (0..config.slots.len())
+        .filter_map(|number| get_slot(config, states, number))
+        .collect()
+}
@@ -0,0 +1,50 @@
+use crate::config::ConfigData;
+#[cfg(feature = "owfs")]
+use std::fs;
+#[cfg(feature = "owfs")]
+use tracing::warn;
+
+/// Nothing the machine sits in gets this hot, so a reading past it has to
+/// be in millidegrees
+#[cfg(feature = "owfs")]
+const MAX_PLAUSIBLE_DEGREES: f32 = 1000.0;
+
+/// `None` when there's no sensor or it couldn't be read
+pub fn get_temperature(config: &ConfigData) -> Option<f32> {
+    #[cfg(any(test, feature = "mock"))]
+    if config.mock {
+        return Some(crate::mock::temperature());
+    }
+    read_sensor(config)
+}
+
+#[cfg(feature = "owfs")]
+fn read_sensor(config: &ConfigData) -> Option<f32> {
+    let temperature_id = config.temperature_id.as_ref()?;
+    let path = format!("/mnt/w1/{}/temperature12", temperature_id);
+    let temperature = fs::read_to_string(path.clone());
+
+    match temperature {
+        Ok(temperature) => match temperature.trim_end().parse::<f32>() {
+            Ok(temperature) if config.temp_unit_millidegrees => Some(temperature / 1000.0),
+            Ok(temperature) if temperature.abs() > MAX_PLAUSIBLE_DEGREES => {
+                Some(temperature / 1000.0)
+            }
+            Ok(temperature) => Some(temperature),
+            Err(err) => {
+                warn!("Temperature sensor {} errored out: {:?}", path, err);
+                None
+            }
+        },
+        Err(_) => {
+            warn!("Temperature sensor {} doesn't exist!", path);
+            None
+        }
+    }
+}
+
+/// Without OWFS there's no sensor to read (and config won't accept one)
+#[cfg(not(feature = "owfs"))]
+fn read_sensor(_config: &ConfigData) -> Option<f32> {
+    None
+}
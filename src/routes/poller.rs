@@ -0,0 +1,297 @@
+use actix_web::web;
+use chrono::{SecondsFormat, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use super::events::MachineEvent;
+use super::history::StockEvent;
+use super::machine::status::{self, is_stocked};
+use super::slot_stats::SlotStatsRegistry;
+use crate::config::{AppData, ConfigData, SlotConfig};
+
+#[cfg(feature = "gpio")]
+use futures::future;
+#[cfg(feature = "gpio")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "gpio")]
+use std::sync::Arc;
+#[cfg(feature = "gpio")]
+use tokio::io::unix::AsyncFd;
+#[cfg(feature = "gpio")]
+use tracing::warn;
+
+/// The stock poller and GPIO watchers, which a config reload stops so they
+/// let go of the old slots, then starts again on the new ones
+pub struct Monitors {
+    cancel: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Monitors {
+    pub fn start(data: web::Data<AppData>, slot_stats: web::Data<SlotStatsRegistry>) -> Self {
+        let cancel = CancellationToken::new();
+        let tasks = vec![
+            tokio::spawn(poll_stock(data.clone(), slot_stats.clone(), cancel.clone())),
+            #[cfg(feature = "gpio")]
+            tokio::spawn(event_driven_monitor(data, slot_stats, cancel.clone())),
+        ];
+        Monitors { cancel, tasks }
+    }
+
+    /// Returns once every task has finished
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        for task in self.tasks {
+            // A task that panicked has let go of its slots all the same
+            let _ = task.await;
+        }
+    }
+}
+
+/// Watches the stocked switches that can't tell us when they change. GPIO
+/// slots are left to `event_driven_monitor`.
+async fn poll_stock(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    cancel: CancellationToken,
+) {
+    let (interval, polled) = {
+        let config = data.config();
+        let polled = config.slots.iter().any(|slot| needs_polling(slot));
+        (config.stock_poll_ms, polled)
+    };
+    if !polled {
+        return;
+    }
+    if interval == 0 {
+        info!("Stock polling is off");
+        return;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_millis(interval));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            () = cancel.cancelled() => return,
+        }
+        check_stock(&data, &slot_stats, |_, slot| needs_polling(slot)).await;
+    }
+}
+
+fn needs_polling(slot: &SlotConfig) -> bool {
+    match slot {
+        #[cfg(feature = "owfs")]
+        SlotConfig::OWFS(_) | SlotConfig::OwfsGpio { .. } | SlotConfig::OwfsRedundant { .. } => {
+            true
+        }
+        #[cfg(feature = "gpio")]
+        SlotConfig::GPIO { .. } => false,
+        // Nothing to wait on, so the poller is how drops from them show up
+        #[cfg(any(test, feature = "mock"))]
+        SlotConfig::Mock(_) => true,
+    }
+}
+
+/// Lends the stocked line's fd to `AsyncFd`, keeping the slot alive for as
+/// long as it's registered
+#[cfg(feature = "gpio")]
+struct StockedEvents {
+    fd: RawFd,
+    _slot: Arc<SlotConfig>,
+}
+
+#[cfg(feature = "gpio")]
+impl StockedEvents {
+    /// `None` for slots that aren't on GPIO
+    fn new(slot: &Arc<SlotConfig>) -> Option<Self> {
+        match &**slot {
+            SlotConfig::GPIO { stocked, .. } => Some(StockedEvents {
+                fd: stocked.as_raw_fd(),
+                _slot: slot.clone(),
+            }),
+            #[cfg(any(feature = "owfs", test, feature = "mock"))]
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "gpio")]
+impl AsRawFd for StockedEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Waits for edges on every GPIO slot's stocked switch and checks that slot
+/// as soon as one comes in, instead of waiting for the next poll. Finishes
+/// once `cancel` fires and every watcher has stopped.
+#[cfg(feature = "gpio")]
+async fn event_driven_monitor(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    cancel: CancellationToken,
+) {
+    let slots: Vec<(usize, StockedEvents)> = data
+        .config()
+        .slots
+        .iter()
+        .enumerate()
+        .filter_map(|(number, slot)| Some((number, StockedEvents::new(slot)?)))
+        .collect();
+    let watchers = slots.into_iter().map(|(number, events)| {
+        tokio::spawn(watch_slot(
+            data.clone(),
+            slot_stats.clone(),
+            number,
+            events,
+            cancel.clone(),
+        ))
+    });
+    future::join_all(watchers).await;
+}
+
+#[cfg(feature = "gpio")]
+async fn watch_slot(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    number: usize,
+    events: StockedEvents,
+    cancel: CancellationToken,
+) {
+    // Same as AsyncLineEventHandle does, AsyncFd needs reads to not block
+    unsafe {
+        let flags = libc::fcntl(events.fd, libc::F_GETFL, 0);
+        libc::fcntl(events.fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    let events = match AsyncFd::new(events) {
+        Ok(events) => events,
+        Err(err) => {
+            warn!("Can't watch slot {}'s stocked pin: {}", number, err);
+            return;
+        }
+    };
+    // Take a baseline before anything changes
+    check_stock(&data, &slot_stats, |slot, _| slot == number).await;
+    loop {
+        let readable = tokio::select! {
+            readable = events.readable() => readable,
+            () = cancel.cancelled() => return,
+        };
+        let mut guard = match readable {
+            Ok(guard) => guard,
+            Err(err) => {
+                warn!("Stopped watching slot {}'s stocked pin: {}", number, err);
+                return;
+            }
+        };
+        // We only care that something happened, the switch gets read again
+        // after debouncing anyway
+        let drained = guard.try_io(|events| {
+            let mut buf = [0u8; 256];
+            loop {
+                let read = unsafe {
+                    libc::read(
+                        events.get_ref().fd,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                // Until it would block, which clears the readiness too
+                if read < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if read == 0 {
+                    return Ok(());
+                }
+            }
+        });
+        if let Ok(Err(err)) = drained {
+            warn!(
+                "Couldn't read slot {}'s stocked pin events: {}",
+                number, err
+            );
+        }
+        check_stock(&data, &slot_stats, |slot, _| slot == number).await;
+    }
+}
+
+/// Compares the slots `wanted` picks against what we saw last, and logs the
+/// ones that changed. The first look at each slot just sets the baseline.
+async fn check_stock<F>(data: &AppData, slot_stats: &SlotStatsRegistry, wanted: F)
+where
+    F: Fn(usize, &SlotConfig) -> bool,
+{
+    let (changed, debounce_ms) = {
+        let config = data.config();
+        let mut states = slot_stats.lock().await;
+        let mut changed = HashMap::new();
+        for (slot, stocked) in read_all(&config, &wanted) {
+            let state = states.entry(slot).or_default();
+            match state.last_stocked {
+                None => state.last_stocked = Some(stocked),
+                Some(last) if last != stocked => {
+                    changed.insert(slot, stocked);
+                }
+                Some(_) => {}
+            }
+        }
+        (changed, config.stocked_debounce_ms)
+    };
+    if changed.is_empty() {
+        return;
+    }
+
+    // Switches bounce when a drink is halfway out, so only believe a
+    // change that's still there a little later
+    tokio::time::sleep(Duration::from_millis(debounce_ms)).await;
+    let config = data.config();
+    let mut states = slot_stats.lock().await;
+    for (slot, stocked) in read_all(&config, |slot, _| changed.contains_key(&slot)) {
+        if changed.get(&slot) != Some(&stocked) {
+            continue;
+        }
+        let state = states.entry(slot).or_default();
+        if state.last_stocked.replace(stocked) == Some(stocked) {
+            continue;
+        }
+        let slot_config = match config.get_slot(slot) {
+            Some(slot_config) => slot_config,
+            None => continue,
+        };
+        info!(
+            "Slot {} ({}) is now {}",
+            slot,
+            slot_config,
+            if stocked { "stocked" } else { "empty" }
+        );
+        data.stock_events.lock().await.record(StockEvent {
+            slot,
+            slot_id: slot_config.to_string(),
+            changed_to: stocked,
+            detected_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        });
+        if let Some(status) = status::get_slot(&config, &states, slot) {
+            data.emit(MachineEvent::SlotChanged(status));
+        }
+    }
+}
+
+/// The `wanted` slots whose switch could be read, and what it said
+fn read_all<F>(config: &ConfigData, wanted: F) -> Vec<(usize, bool)>
+where
+    F: Fn(usize, &SlotConfig) -> bool,
+{
+    config
+        .slots
+        .iter()
+        .enumerate()
+        .filter(|(slot, slot_config)| wanted(*slot, slot_config))
+        // get_slots already complains about read failures
+        .filter_map(|(slot, slot_config)| {
+            let stocked = is_stocked(slot_config, config.stocked_inverted(slot)).ok()?;
+            Some((slot, stocked))
+        })
+        .collect()
+}
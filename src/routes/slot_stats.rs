@@ -0,0 +1,54 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{Mutex, MutexGuard};
+use utoipa::ToSchema;
+
+use super::history::CamPulseRecord;
+
+/// Per-slot state that lives for as long as the server does, as opposed to
+/// the hardware config
+#[derive(Default)]
+pub struct SlotState {
+    /// Forced stocked status, set by an operator
+    pub stocked_override: Option<bool>,
+    pub meta: SlotMeta,
+    /// Oldest first, at most `ConfigData::cam_history_size` long
+    pub cam_history: VecDeque<CamPulseRecord>,
+    /// ETag of the last `GET /slots/{id}` response
+    pub last_etag: Option<String>,
+    /// What the stock poller saw last time, `None` before its first look
+    pub last_stocked: Option<bool>,
+    /// The last `GET /slots/{id}/stocked` answer
+    pub last_stocked_check: Option<StockedCheck>,
+    /// Successful drops since the server started
+    pub drop_count: u64,
+}
+
+/// One reading of a slot's stocked switch
+#[derive(Clone, Serialize, ToSchema)]
+pub struct StockedCheck {
+    pub slot: usize,
+    pub stocked: bool,
+    pub checked_at: String,
+}
+
+/// What's in the slot, as far as the people stocking it have told us
+#[derive(Clone, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SlotMeta {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub price_cents: Option<u32>,
+}
+
+/// Everything we know about each slot beyond its hardware config. Lives
+/// outside `AppData` so handlers that only care about slots don't need it.
+#[derive(Default)]
+pub struct SlotStatsRegistry {
+    states: Mutex<HashMap<usize, SlotState>>,
+}
+
+impl SlotStatsRegistry {
+    pub async fn lock(&self) -> MutexGuard<'_, HashMap<usize, SlotState>> {
+        self.states.lock().await
+    }
+}
@@ -0,0 +1,275 @@
+//! The routes, run against mock slots
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{test, web, App};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::slot_stats::SlotStatsRegistry;
+use crate::config::{AppData, ConfigData, Settings};
+
+/// A mock machine with a quick cam, plus whatever `vars` add or override
+fn mock_data(vars: &[(&'static str, &str)]) -> web::Data<AppData> {
+    let mut all = vec![
+        ("BUB_MOCK", "1"),
+        ("BUB_MOCK_SLOTS", "2"),
+        ("BUB_MOCK_CAM_MS", "10"),
+        ("BUB_MULTI_DROP_INTERVAL_MS", "0"),
+    ];
+    all.extend_from_slice(vars);
+    let settings = Settings::from_vars(&all);
+    let config = ConfigData::new(&settings).expect("mock config is valid");
+    web::Data::new(AppData::new(config, settings, false))
+}
+
+fn app(
+    data: web::Data<AppData>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(data)
+        .app_data(web::Data::new(SlotStatsRegistry::default()))
+        .service(super::drop)
+        .service(super::get_drops)
+        .service(super::clear_drops)
+        .service(super::get_slots)
+        .service(super::get_slot)
+        .service(super::get_temperature)
+        .service(super::set_offline)
+        .service(super::reboot)
+        .service(super::emergency_stop)
+        .service(super::swagger_ui)
+        .service(super::swagger_ui_css)
+        .service(super::swagger_ui_bundle)
+}
+
+fn drop_request(body: Value) -> test::TestRequest {
+    test::TestRequest::post().uri("/drop").set_json(body)
+}
+
+#[actix_web::test]
+async fn slots_are_mocked() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let req = test::TestRequest::get().uri("/slots").to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["total_slots"], 2);
+    assert_eq!(report["stocked_slots_count"], 2);
+    assert_eq!(report["slots"][0]["id"], "mock0");
+    assert_eq!(report["slots"][1]["id"], "mock1");
+}
+
+#[actix_web::test]
+async fn drop_turns_the_cam() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 1})).to_request()).await;
+    assert_eq!(res.status(), 200);
+    let dropped: Value = test::read_body_json(res).await;
+    assert_eq!(dropped["slot"], 1);
+    assert_eq!(dropped["slot_id"], "mock1");
+    assert_eq!(dropped["drop_method"], "cam_detected");
+    assert!(dropped["cam_ms"].as_u64().is_some());
+}
+
+#[actix_web::test]
+async fn drops_use_up_the_stock() {
+    let app = test::init_service(app(mock_data(&[("BUB_MOCK_STOCK", "2")]))).await;
+    let req = drop_request(json!({"slot_number": 0, "count": 2})).to_request();
+    let drops: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(drops["results"].as_array().map(Vec::len), Some(2));
+
+    let req = test::TestRequest::get().uri("/slots").to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["slots"][0]["stocked"], false);
+    assert_eq!(report["slots"][1]["stocked"], true);
+}
+
+#[actix_web::test]
+async fn drop_past_the_last_slot_is_refused() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 2})).to_request()).await;
+    assert_eq!(res.status(), 422);
+}
+
+#[actix_web::test]
+async fn drop_takes_per_request_options() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let body = json!({
+        "slot_number": 0,
+        "operator_id": "tester",
+        "skip_stocked_check": true,
+        "custom_delay_ms": 1,
+    });
+    let res = test::call_service(&app, drop_request(body).to_request()).await;
+    assert_eq!(res.status(), 200);
+
+    let req = test::TestRequest::post()
+        .uri("/drop")
+        .set_form([("slot_number", "1"), ("skip_stocked_check", "true")])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let body = json!({"slot_number": 0, "custom_delay_ms": 60_001});
+    let res = test::call_service(&app, drop_request(body).to_request()).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[actix_web::test]
+async fn slot_past_the_last_is_unprocessable() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let req = test::TestRequest::get().uri("/slots/1").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    let req = test::TestRequest::get().uri("/slots/2").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 422);
+    let error: Value = test::read_body_json(res).await;
+    assert_eq!(error["errorCode"], 422);
+}
+
+#[actix_web::test]
+async fn offline_machine_refuses_drops() {
+    let app = test::init_service(app(mock_data(&[("BUB_API_KEY", "hunter2")]))).await;
+    let req = test::TestRequest::put()
+        .uri("/machine/offline")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 0})).to_request()).await;
+    assert_eq!(res.status(), 200);
+
+    let req = test::TestRequest::put()
+        .uri("/machine/offline")
+        .insert_header(("Authorization", "Bearer hunter2"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 0})).to_request()).await;
+    assert_eq!(res.status(), 503);
+}
+
+#[actix_web::test]
+async fn reloading_machine_refuses_drops_and_slot_reads() {
+    let data = mock_data(&[]);
+    data.reloading.store(true, Ordering::SeqCst);
+    let app = test::init_service(app(data.clone())).await;
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 0})).to_request()).await;
+    assert_eq!(res.status(), 503);
+    assert!(res.headers().contains_key("Retry-After"));
+    let req = test::TestRequest::get().uri("/slots").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 503);
+
+    data.reloading.store(false, Ordering::SeqCst);
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 0})).to_request()).await;
+    assert_eq!(res.status(), 200);
+}
+
+#[actix_web::test]
+async fn temperature_is_mocked() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let req = test::TestRequest::get().uri("/temperature").to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+    let celsius = report["celsius"]
+        .as_f64()
+        .expect("mock sensor always reads");
+    assert!((3.0..=5.0).contains(&celsius));
+    assert_eq!(report["sensor_id"], "mock");
+}
+
+#[actix_web::test]
+async fn swagger_ui_is_served_locally() {
+    let app = test::init_service(app(mock_data(&[]))).await;
+    let req = test::TestRequest::get().uri("/swagger-ui").to_request();
+    let page = test::call_and_read_body(&app, req).await;
+    let page = std::str::from_utf8(&page).expect("page is html");
+    assert!(!page.contains("://"));
+
+    for asset in [
+        "/swagger-ui/swagger-ui.css",
+        "/swagger-ui/swagger-ui-bundle.js",
+    ] {
+        let req = test::TestRequest::get().uri(asset).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200, "{}", asset);
+    }
+}
+
+#[actix_web::test]
+async fn clearing_drops_needs_the_api_key() {
+    let app = test::init_service(app(mock_data(&[("BUB_API_KEY", "hunter2")]))).await;
+    let res = test::call_service(&app, drop_request(json!({"slot_number": 0})).to_request()).await;
+    assert_eq!(res.status(), 200);
+
+    let req = test::TestRequest::delete()
+        .uri("/drops/history")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    let req = test::TestRequest::delete()
+        .uri("/drops/history?before=2000-01-01T00:00:00Z")
+        .insert_header(("Authorization", "Bearer hunter2"))
+        .to_request();
+    let cleared: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cleared["cleared"], 0);
+
+    let req = test::TestRequest::delete()
+        .uri("/drops/history")
+        .insert_header(("Authorization", "Bearer hunter2"))
+        .to_request();
+    let cleared: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cleared["cleared"], 1);
+
+    let req = test::TestRequest::get().uri("/drops").to_request();
+    let drops: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(drops, json!([]));
+}
+
+#[actix_web::test]
+async fn reboot_needs_the_api_key() {
+    let data = mock_data(&[("BUB_API_KEY", "hunter2"), ("BUB_ALLOW_REMOTE_REBOOT", "1")]);
+    let app = test::init_service(app(data.clone())).await;
+    let req = test::TestRequest::post()
+        .uri("/machine/reboot")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = test::TestRequest::post()
+        .uri("/machine/reboot")
+        .insert_header(("Authorization", "Bearer hunter3"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    assert!(!data.offline.load(Ordering::SeqCst));
+}
+
+#[actix_web::test]
+async fn emergency_stop_needs_the_api_key() {
+    let data = mock_data(&[("BUB_API_KEY", "hunter2")]);
+    let app = test::init_service(app(data.clone())).await;
+    let req = test::TestRequest::put()
+        .uri("/machine/emergency-stop")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    assert!(!data.offline.load(Ordering::SeqCst));
+
+    let req = test::TestRequest::put()
+        .uri("/machine/emergency-stop")
+        .insert_header(("Authorization", "Bearer hunter2"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    assert!(data.offline.load(Ordering::SeqCst));
+}
+
+#[actix_web::test]
+async fn in_progress_clears_even_on_panic() {
+    let flag = AtomicBool::new(false);
+    let panicked = std::panic::catch_unwind(|| {
+        let _in_progress = super::InProgress::start(&flag);
+        assert!(flag.load(Ordering::SeqCst));
+        panic!("motor write blew up");
+    });
+    assert!(panicked.is_err());
+    assert!(!flag.load(Ordering::SeqCst));
+}
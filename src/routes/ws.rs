@@ -0,0 +1,149 @@
+use actix_web::{get, rt, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+
+use super::machine::drop::DropOptions;
+use super::machine::status;
+use super::slot_stats::SlotStatsRegistry;
+use super::{
+    build_machine_state, check_api_key, perform_drop, read_temperature, AppData, DropErrorRes,
+    DropResponse, MachineState, SlotReport,
+};
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Drop {
+        #[serde(alias = "slot")]
+        slot_number: usize,
+    },
+    RefreshSlots,
+}
+
+/// Sent back to the client that asked. Tagged the same way as
+/// `MachineEvent` so clients can treat everything as one stream.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Reply {
+    State(MachineState),
+    Slots(SlotReport),
+    DropResponse(DropResponse),
+    Error(DropErrorRes),
+}
+
+/// Sends the machine state on connect, then pushes every `MachineEvent` and
+/// answers `{"cmd": "drop", "slot_number": N}` and `{"cmd": "refresh_slots"}`
+#[get("/ws")]
+pub async fn ws(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(res) = check_api_key(&req, &data.config()) {
+        return Ok(res.respond());
+    }
+
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+    rt::spawn(run_session(data, slot_stats, session, stream));
+    Ok(response)
+}
+
+async fn send<T: Serialize>(session: &mut Session, message: &T) -> Result<(), actix_ws::Closed> {
+    match serde_json::to_string(message) {
+        Ok(text) => session.text(text).await,
+        // Everything we send is our own types, so this would be a bug here
+        // rather than anything the client did
+        Err(err) => {
+            error!("Couldn't serialize websocket message: {}", err);
+            Ok(())
+        }
+    }
+}
+
+async fn handle_command(data: &AppData, slot_stats: &SlotStatsRegistry, text: &str) -> Reply {
+    let command = match serde_json::from_str::<Command>(text) {
+        Ok(command) => command,
+        Err(err) => {
+            return Reply::Error(DropErrorRes {
+                error: format!("Bad command: {}", err),
+                errorCode: 400,
+                retry_after: None,
+                details: Vec::new(),
+            })
+        }
+    };
+    match command {
+        Command::Drop { slot_number } => {
+            match perform_drop(data, slot_stats, slot_number, DropOptions::default(), None).await {
+                Ok(res) => Reply::DropResponse(res),
+                Err(res) => Reply::Error(res),
+            }
+        }
+        Command::RefreshSlots => {
+            let drop_in_progress = data.drop_in_progress.load(Ordering::SeqCst);
+            let config = data.config();
+            let slots = status::get_slots(config.deref(), &*slot_stats.lock().await);
+            let temp = read_temperature(data, config.deref()).await;
+            Reply::Slots(SlotReport::new(
+                config.deref(),
+                slots,
+                temp,
+                drop_in_progress,
+            ))
+        }
+    }
+}
+
+async fn run_session(
+    data: web::Data<AppData>,
+    slot_stats: web::Data<SlotStatsRegistry>,
+    mut session: Session,
+    mut stream: MessageStream,
+) {
+    let mut events = data.events.subscribe();
+    let state = build_machine_state(&data, &slot_stats, None).await;
+    if send(&mut session, &Reply::State(state)).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            message = stream.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle_command(&data, &slot_stats, &text).await;
+                    if send(&mut session, &reply).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    warn!("Websocket client sent garbage, hanging up: {}", err);
+                    break;
+                }
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if send(&mut session, &event).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Websocket client fell behind and missed {} events", missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    let _ = session.close(None).await;
+}
@@ -1,5 +1,49 @@
-use libc::{sched_param, sched_setscheduler, SCHED_FIFO, SCHED_OTHER};
-use std::{io, marker::PhantomData};
+use libc::{
+    sched_getparam, sched_getscheduler, sched_param, sched_setscheduler, SCHED_FIFO, SCHED_OTHER,
+    SCHED_RR,
+};
+use std::{fmt::Display, io, marker::PhantomData, thread};
+use tracing::warn;
+
+pub const REALTIME_PRIORITY: i32 = 10;
+
+/// Which real-time policy drops run under, from `BUB_RT_POLICY`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RtPolicy {
+    /// `SCHED_FIFO`, runs until it yields
+    #[default]
+    Fifo,
+    /// `SCHED_RR`, time-sliced so other real-time work on the Pi still gets a turn
+    RoundRobin,
+}
+
+impl RtPolicy {
+    /// The `SCHED_*` constant for this policy
+    fn sched_policy(self) -> i32 {
+        match self {
+            Self::Fifo => SCHED_FIFO,
+            Self::RoundRobin => SCHED_RR,
+        }
+    }
+}
+
+impl Display for RtPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fifo => write!(f, "fifo"),
+            Self::RoundRobin => write!(f, "rr"),
+        }
+    }
+}
+
+fn set_scheduler(policy: i32, sched_priority: i32) -> io::Result<()> {
+    let params = sched_param { sched_priority };
+    let res = unsafe { sched_setscheduler(0, policy, &params) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
 
 pub struct RealtimeGuard {
     marker: PhantomData<*const ()>,
@@ -7,32 +51,108 @@ pub struct RealtimeGuard {
 
 impl Drop for RealtimeGuard {
     fn drop(&mut self) {
-        self.set_priority(false)
-            .expect("Couldn't drop real-time priority!");
+        set_scheduler(SCHED_OTHER, 0).expect("Couldn't drop real-time priority!");
     }
 }
 
-impl Default for RealtimeGuard {
-    fn default() -> Self {
-        let mut guard = Self {
+impl RealtimeGuard {
+    /// Hands back the error instead of panicking when we aren't allowed
+    /// real-time priority
+    pub fn new(policy: RtPolicy) -> io::Result<Self> {
+        set_scheduler(policy.sched_policy(), REALTIME_PRIORITY)?;
+        Ok(Self {
             marker: PhantomData,
-        };
-        guard
-            .set_priority(true)
-            .expect("Couldn't escalate to real-time priority!");
-        guard
+        })
     }
-}
 
-impl RealtimeGuard {
-    fn set_priority(&mut self, real_time: bool) -> io::Result<()> {
-        let policy = if real_time { SCHED_FIFO } else { SCHED_OTHER };
-        let sched_priority = if real_time { 10 } else { 0 };
-        let params = sched_param { sched_priority };
-        let res = unsafe { sched_setscheduler(0, policy, &params) };
+    /// Like `new`, but panics when we aren't allowed real-time priority
+    pub fn with_policy(policy: RtPolicy) -> Self {
+        Self::new(policy).expect("Couldn't escalate to real-time priority!")
+    }
+
+    /// Scheduling priority of the calling thread
+    pub fn current_priority() -> io::Result<i32> {
+        let mut params = sched_param { sched_priority: 0 };
+        let res = unsafe { sched_getparam(0, &mut params) };
         if res == -1 {
             return Err(io::Error::last_os_error());
         }
-        Ok(())
+        Ok(params.sched_priority)
+    }
+
+    /// Scheduling policy (`SCHED_*`) of the calling thread
+    pub fn current_policy() -> io::Result<i32> {
+        let policy = unsafe { sched_getscheduler(0) };
+        if policy == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(policy)
+    }
+
+    /// Whether the calling thread has a real-time policy, FIFO or round-robin
+    pub fn is_realtime() -> io::Result<bool> {
+        let policy = Self::current_policy()?;
+        Ok(policy == SCHED_FIFO || policy == SCHED_RR)
+    }
+}
+
+/// Whether drops can get real-time priority under `policy`. Tried on a
+/// thread of its own, so no other thread's scheduling changes. `None` if we
+/// couldn't tell.
+pub fn probe_realtime(policy: RtPolicy) -> Option<bool> {
+    thread::spawn(move || match RealtimeGuard::new(policy) {
+        Ok(_rt) => RealtimeGuard::is_realtime()
+            .map_err(|err| warn!("Couldn't read scheduler policy: {}", err))
+            .ok(),
+        Err(err) => {
+            warn!("Can't get {} real-time priority: {}", policy, err);
+            Some(false)
+        }
+    })
+    .join()
+    .ok()
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guard_switches_policy_and_back() {
+        // Real-time priority needs CAP_SYS_NICE (or an rtprio limit), which
+        // CI and dev machines usually don't give us. Dropping the guard
+        // straight away puts the thread back how it was.
+        if RealtimeGuard::new(RtPolicy::Fifo).is_err() {
+            eprintln!("Skipping, not allowed real-time priority");
+            return;
+        }
+        for (policy, sched_policy) in [
+            (RtPolicy::Fifo, SCHED_FIFO),
+            (RtPolicy::RoundRobin, SCHED_RR),
+        ] {
+            {
+                let _rt = RealtimeGuard::with_policy(policy);
+                assert_eq!(RealtimeGuard::current_policy().unwrap(), sched_policy);
+                assert_eq!(
+                    RealtimeGuard::current_priority().unwrap(),
+                    REALTIME_PRIORITY
+                );
+                assert!(RealtimeGuard::is_realtime().unwrap());
+            }
+            assert_eq!(RealtimeGuard::current_policy().unwrap(), SCHED_OTHER);
+            assert!(!RealtimeGuard::is_realtime().unwrap());
+        }
+    }
+
+    #[test]
+    fn probe_leaves_the_calling_thread_alone() {
+        let before = RealtimeGuard::current_policy().unwrap();
+        let capable = probe_realtime(RtPolicy::RoundRobin);
+        assert_eq!(RealtimeGuard::current_policy().unwrap(), before);
+        assert_eq!(
+            capable,
+            Some(RealtimeGuard::new(RtPolicy::RoundRobin).is_ok())
+        );
     }
 }